@@ -0,0 +1,524 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use bincode::{config::standard, Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    merkle::{self, Hash, MerkleProof},
+    Clock, Market, MarketConfig, OrderBookError, OrderBookResult, OrderBookState,
+    OrderLocation, OrderType, Price, PriceLevel, TriggerCondition, TriggerQueue,
+};
+
+/// What the contract state digest holds.
+///
+/// The few fields every action may read stay in clear. Balances, markets, price levels and the
+/// order index grow with users and orders, so they are leaves of a sparse Merkle tree of which
+/// only the root is kept. A proof then only carries the leaves its action touches, see
+/// [`StateWitness`].
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateCommitment {
+    pub admin: String,
+    pub fee_collector: String,
+    pub order_nonce: u64,
+    /// Orders expire by it, see [`Clock`]
+    pub clock: Clock,
+    /// Root of the tree holding every [`LeafKey`] the state has a value for
+    pub root: Hash,
+}
+
+/// Position of a value in the state tree, its path being the hash of its encoding
+#[derive(
+    Encode, Decode, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum LeafKey {
+    /// Only non-zero balances are in the tree
+    Balance { identity: String, asset: String },
+    Market(String),
+    /// Only non-empty levels are in the tree
+    Level {
+        market: String,
+        order_type: OrderType,
+        price: Price,
+    },
+    Order(u64),
+    /// Only non-empty queues are in the tree
+    Triggers {
+        market: String,
+        condition: TriggerCondition,
+        price: Price,
+    },
+}
+
+/// Leaves an action wrote with their new value, `None` for a leaf it removed
+pub type StateChanges = Vec<(LeafKey, Option<LeafValue>)>;
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LeafValue {
+    Balance(u128),
+    Market(Box<MarketLeaf>),
+    Level(PriceLevel),
+    Order(OrderLocation),
+    Triggers(TriggerQueue),
+}
+
+/// A market without its orders, which are in the leaves of their price levels and trigger queues.
+///
+/// Knowing every occupied price lets the contract tell a level it was not shown from one that
+/// does not exist, so a witness can't hide the best price from the matching loop, nor a reached
+/// trigger from the trades that fire it.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketLeaf {
+    pub config: MarketConfig,
+    pub ask_prices: Vec<Price>,
+    pub bid_prices: Vec<Price>,
+    pub trigger_above_prices: Vec<Price>,
+    pub trigger_below_prices: Vec<Price>,
+    pub last_trade_price: Option<Price>,
+}
+
+/// Leaves of the state an action reads or writes, with the proof that they are in the committed tree
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Default)]
+pub struct StateWitness {
+    /// `None` for a key the tree has no value for
+    pub leaves: Vec<(LeafKey, Option<LeafValue>)>,
+    pub proof: MerkleProof,
+}
+
+impl LeafKey {
+    fn path(&self) -> Hash {
+        merkle::key_hash(&encode(self))
+    }
+}
+
+fn encode<T: Encode>(value: &T) -> Vec<u8> {
+    bincode::encode_to_vec(value, standard()).expect("Failed to encode state leaf")
+}
+
+fn leaf_hash(path: &Hash, value: Option<&LeafValue>) -> Hash {
+    value.map_or(merkle::EMPTY, |value| merkle::leaf_hash(path, &encode(value)))
+}
+
+/// Every leaf of `state`.
+///
+/// Levels and trigger queues left empty, which a state opened by [`open`] uses for the ones it
+/// was not shown, are skipped along with zero balances.
+pub fn leaves(state: &OrderBookState) -> BTreeMap<LeafKey, LeafValue> {
+    let mut leaves = BTreeMap::new();
+
+    for (identity, balances) in &state.balances {
+        for (asset, amount) in balances.iter().filter(|(_, amount)| **amount > 0) {
+            leaves.insert(
+                LeafKey::Balance {
+                    identity: identity.clone(),
+                    asset: asset.clone(),
+                },
+                LeafValue::Balance(*amount),
+            );
+        }
+    }
+
+    for (name, market) in &state.markets {
+        leaves.insert(
+            LeafKey::Market(name.clone()),
+            LeafValue::Market(Box::new(MarketLeaf {
+                config: market.config.clone(),
+                ask_prices: market.ask_levels.keys().copied().collect(),
+                bid_prices: market.bid_levels.keys().copied().collect(),
+                trigger_above_prices: market.triggers_above.keys().copied().collect(),
+                trigger_below_prices: market.triggers_below.keys().copied().collect(),
+                last_trade_price: market.last_trade_price,
+            })),
+        );
+
+        for (order_type, levels) in [
+            (OrderType::Ask, &market.ask_levels),
+            (OrderType::Bid, &market.bid_levels),
+        ] {
+            for (price, level) in levels.iter().filter(|(_, level)| !level.is_empty()) {
+                leaves.insert(
+                    LeafKey::Level {
+                        market: name.clone(),
+                        order_type: order_type.clone(),
+                        price: *price,
+                    },
+                    LeafValue::Level(level.clone()),
+                );
+            }
+        }
+
+        for (condition, queues) in [
+            (TriggerCondition::AtOrAbove, &market.triggers_above),
+            (TriggerCondition::AtOrBelow, &market.triggers_below),
+        ] {
+            for (price, queue) in queues.iter().filter(|(_, queue)| !queue.is_empty()) {
+                leaves.insert(
+                    LeafKey::Triggers {
+                        market: name.clone(),
+                        condition,
+                        price: *price,
+                    },
+                    LeafValue::Triggers(queue.clone()),
+                );
+            }
+        }
+    }
+
+    for (order_id, location) in &state.order_index {
+        leaves.insert(LeafKey::Order(*order_id), LeafValue::Order(location.clone()));
+    }
+
+    leaves
+}
+
+/// `(path, leaf hash)` of every leaf of `state`, sorted by path as [`merkle`] expects
+fn hashed_leaves(state: &OrderBookState) -> Vec<(Hash, Hash)> {
+    let mut hashed: Vec<(Hash, Hash)> = leaves(state)
+        .iter()
+        .map(|(key, value)| {
+            let path = key.path();
+            (path, leaf_hash(&path, Some(value)))
+        })
+        .collect();
+    hashed.sort();
+    hashed
+}
+
+fn with_root(state: &OrderBookState, root: Hash) -> StateCommitment {
+    StateCommitment {
+        admin: state.admin.clone(),
+        fee_collector: state.fee_collector.clone(),
+        order_nonce: state.order_nonce,
+        clock: state.clock,
+        root,
+    }
+}
+
+/// Commitment to a full state, as kept by the host
+pub fn commit(state: &OrderBookState) -> StateCommitment {
+    with_root(state, merkle::root(&hashed_leaves(state)))
+}
+
+/// Reveals `keys` of a full state
+pub fn witness(state: &OrderBookState, keys: &BTreeSet<LeafKey>) -> StateWitness {
+    let leaves = leaves(state);
+    let mut paths: Vec<Hash> = keys.iter().map(LeafKey::path).collect();
+    paths.sort();
+
+    StateWitness {
+        leaves: keys
+            .iter()
+            .map(|key| (key.clone(), leaves.get(key).cloned()))
+            .collect(),
+        proof: merkle::prove(&hashed_leaves(state), &paths),
+    }
+}
+
+/// Keys whose value differs between two full states
+pub fn changed_keys(before: &OrderBookState, after: &OrderBookState) -> BTreeSet<LeafKey> {
+    let (before, after) = (leaves(before), leaves(after));
+    before
+        .keys()
+        .chain(after.keys())
+        .filter(|key| before.get(key) != after.get(key))
+        .cloned()
+        .collect()
+}
+
+/// `(path, old leaf hash, new leaf hash)` of the witnessed keys, sorted by path
+fn entries(
+    witness: &StateWitness,
+    new_value: impl Fn(&LeafKey) -> Option<LeafValue>,
+) -> Vec<(Hash, Hash, Hash)> {
+    let mut entries: Vec<(Hash, Hash, Hash)> = witness
+        .leaves
+        .iter()
+        .map(|(key, old)| {
+            let path = key.path();
+            (
+                path,
+                leaf_hash(&path, old.as_ref()),
+                leaf_hash(&path, new_value(key).as_ref()),
+            )
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+/// Checks `witness` against `commitment` and builds the part of the state it reveals.
+///
+/// Accessing anything else fails with [`OrderBookError::MissingWitness`]: revealed balances are
+/// kept even at zero, and the levels and trigger queues of a revealed market that the witness
+/// leaves out are empty.
+pub fn open(commitment: &StateCommitment, witness: &StateWitness) -> OrderBookResult<OrderBookState> {
+    // Nothing revealed, nothing to check
+    if !witness.leaves.is_empty() || !witness.proof.is_empty() {
+        let entries = entries(witness, |_| None);
+        let (root, _) = merkle::roots(&entries, &witness.proof).ok_or(OrderBookError::InvalidWitness)?;
+        if root != commitment.root {
+            return Err(OrderBookError::InvalidWitness);
+        }
+    }
+
+    let revealed = witness.leaves.iter().map(|(key, _)| key.clone()).collect();
+    build(commitment, &witness.leaves, Some(revealed))
+}
+
+/// The state made of `leaves`, with the clear fields of `commitment`, `revealed` being the keys of
+/// a partial state
+fn build(
+    commitment: &StateCommitment,
+    leaves: &[(LeafKey, Option<LeafValue>)],
+    revealed: Option<BTreeSet<LeafKey>>,
+) -> OrderBookResult<OrderBookState> {
+    let mut state = OrderBookState::new(commitment.fee_collector.clone(), commitment.admin.clone());
+    state.order_nonce = commitment.order_nonce;
+    state.clock = commitment.clock;
+    state.revealed = revealed;
+
+    for (key, value) in leaves {
+        match (key, value) {
+            (LeafKey::Balance { identity, asset }, None | Some(LeafValue::Balance(_))) => {
+                let amount = match value {
+                    Some(LeafValue::Balance(amount)) => *amount,
+                    _ => 0,
+                };
+                state.balances.entry(identity.clone()).or_default().insert(asset.clone(), amount);
+            }
+            (LeafKey::Market(name), Some(LeafValue::Market(leaf))) => {
+                let mut market = Market::new(leaf.config.clone());
+                for price in &leaf.ask_prices {
+                    market.ask_levels.insert(*price, PriceLevel::new());
+                }
+                for price in &leaf.bid_prices {
+                    market.bid_levels.insert(*price, PriceLevel::new());
+                }
+                for price in &leaf.trigger_above_prices {
+                    market.triggers_above.insert(*price, TriggerQueue::new());
+                }
+                for price in &leaf.trigger_below_prices {
+                    market.triggers_below.insert(*price, TriggerQueue::new());
+                }
+                market.last_trade_price = leaf.last_trade_price;
+                state.markets.insert(name.clone(), market);
+            }
+            (LeafKey::Order(order_id), Some(LeafValue::Order(location))) => {
+                state.order_index.insert(*order_id, location.clone());
+            }
+            (LeafKey::Market(_) | LeafKey::Order(_), None) => {}
+            // Filled in below, once the markets they belong to are known
+            (LeafKey::Level { .. }, None | Some(LeafValue::Level(_))) => {}
+            (LeafKey::Triggers { .. }, None | Some(LeafValue::Triggers(_))) => {}
+            _ => return Err(OrderBookError::InvalidWitness),
+        }
+    }
+
+    for (key, value) in leaves {
+        if let (LeafKey::Level { market, order_type, price }, Some(LeafValue::Level(level))) = (key, value) {
+            if !state.markets.contains_key(market) {
+                state.check_revealed(LeafKey::Market(market.clone()))?;
+                return Err(OrderBookError::InvalidWitness);
+            }
+            let market = state.markets.get_mut(market).unwrap();
+            let slot = market.levels_mut(order_type).get_mut(price).ok_or(OrderBookError::InvalidWitness)?;
+            *slot = level.clone();
+        }
+
+        if let (LeafKey::Triggers { market, condition, price }, Some(LeafValue::Triggers(queue))) = (key, value) {
+            if !state.markets.contains_key(market) {
+                state.check_revealed(LeafKey::Market(market.clone()))?;
+                return Err(OrderBookError::InvalidWitness);
+            }
+            let market = state.markets.get_mut(market).unwrap();
+            let slot = match condition {
+                TriggerCondition::AtOrAbove => market.triggers_above.get_mut(price),
+                TriggerCondition::AtOrBelow => market.triggers_below.get_mut(price),
+            };
+            *slot.ok_or(OrderBookError::InvalidWitness)? = queue.clone();
+        }
+    }
+
+    Ok(state)
+}
+
+/// Commitment to the state an action left and the leaves it changed, `state` having been opened
+/// from `commitment` with `witness`
+pub fn close(
+    commitment: &StateCommitment,
+    state: &OrderBookState,
+    witness: &StateWitness,
+) -> OrderBookResult<(StateCommitment, StateChanges)> {
+    let leaves = leaves(state);
+    let entries = entries(witness, |key| leaves.get(key).cloned());
+
+    // A leaf written without being revealed can't be placed in the tree
+    let revealed: BTreeSet<&LeafKey> = witness.leaves.iter().map(|(key, _)| key).collect();
+    if let Some(key) = leaves.keys().find(|key| !revealed.contains(key)) {
+        return Err(OrderBookError::MissingWitness(key.clone()));
+    }
+
+    let changes = witness
+        .leaves
+        .iter()
+        .filter_map(|(key, old)| {
+            let new = leaves.get(key).cloned();
+            (new != *old).then(|| (key.clone(), new))
+        })
+        .collect();
+
+    if entries.is_empty() {
+        return Ok((with_root(state, commitment.root), changes));
+    }
+    let (_, root) = merkle::roots(&entries, &witness.proof).ok_or(OrderBookError::InvalidWitness)?;
+
+    Ok((with_root(state, root), changes))
+}
+
+/// Brings a full state up to date with the leaves a settled action changed, see [`close`].
+///
+/// The clear fields of the commitment are not part of the changes, the caller takes them from
+/// the contract state once up to date.
+pub fn apply(state: &OrderBookState, changes: &StateChanges) -> OrderBookResult<OrderBookState> {
+    let mut leaves = leaves(state);
+    for (key, value) in changes {
+        match value {
+            Some(value) => leaves.insert(key.clone(), value.clone()),
+            None => leaves.remove(key),
+        };
+    }
+
+    let leaves: Vec<(LeafKey, Option<LeafValue>)> =
+        leaves.into_iter().map(|(key, value)| (key, Some(value))).collect();
+    build(&with_root(state, merkle::EMPTY), &leaves, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balance(identity: &str, asset: &str) -> LeafKey {
+        LeafKey::Balance {
+            identity: identity.to_string(),
+            asset: asset.to_string(),
+        }
+    }
+
+    fn state() -> OrderBookState {
+        let mut state = OrderBookState::new("fees".to_string(), "admin".to_string());
+        state.credit("alice", "TOKA", 100);
+        state.credit("bob", "USDC", 5_000);
+        let config = MarketConfig::for_test("TOKA", "USDC");
+        state
+            .markets
+            .insert(config.market_name(), Market::new(config));
+        state
+    }
+
+    fn keys(keys: &[LeafKey]) -> BTreeSet<LeafKey> {
+        keys.iter().cloned().collect()
+    }
+
+    #[test]
+    fn open_reveals_only_witnessed_leaves() {
+        let state = state();
+        let commitment = commit(&state);
+        let witness = witness(
+            &state,
+            &keys(&[balance("alice", "TOKA"), balance("carol", "TOKA")]),
+        );
+
+        let opened = open(&commitment, &witness).unwrap();
+
+        assert_eq!(opened.balance("alice", "TOKA"), 100);
+        assert_eq!(opened.balance("carol", "TOKA"), 0);
+        assert_eq!(
+            opened.check_revealed(balance("bob", "USDC")),
+            Err(OrderBookError::MissingWitness(balance("bob", "USDC")))
+        );
+        assert_eq!(
+            opened.market("TOKA/USDC").err(),
+            Some(OrderBookError::MissingWitness(LeafKey::Market(
+                "TOKA/USDC".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn open_rejects_tampered_leaves() {
+        let state = state();
+        let commitment = commit(&state);
+        let mut witness = witness(&state, &keys(&[balance("alice", "TOKA")]));
+        witness.leaves[0].1 = Some(LeafValue::Balance(1_000));
+
+        assert_eq!(
+            open(&commitment, &witness).err(),
+            Some(OrderBookError::InvalidWitness)
+        );
+
+        // Hiding a leaf the tree has a value for
+        witness.leaves[0].1 = None;
+        assert_eq!(
+            open(&commitment, &witness).err(),
+            Some(OrderBookError::InvalidWitness)
+        );
+    }
+
+    #[test]
+    fn open_rejects_duplicated_leaves() {
+        let state = state();
+        let commitment = commit(&state);
+        let mut witness = witness(&state, &keys(&[balance("alice", "TOKA")]));
+        witness
+            .leaves
+            .push((balance("alice", "TOKA"), Some(LeafValue::Balance(1_000))));
+
+        assert_eq!(
+            open(&commitment, &witness).err(),
+            Some(OrderBookError::InvalidWitness)
+        );
+    }
+
+    #[test]
+    fn close_refuses_writes_to_unrevealed_leaves() {
+        let state = state();
+        let commitment = commit(&state);
+        let witness = witness(&state, &keys(&[balance("alice", "TOKA")]));
+        let mut opened = open(&commitment, &witness).unwrap();
+
+        opened.credit("carol", "TOKA", 1);
+
+        assert_eq!(
+            close(&commitment, &opened, &witness),
+            Err(OrderBookError::MissingWitness(balance("carol", "TOKA")))
+        );
+    }
+
+    #[test]
+    fn close_and_apply_follow_the_full_state() {
+        let mut state = state();
+        let commitment = commit(&state);
+        let witness = witness(
+            &state,
+            &keys(&[balance("alice", "TOKA"), balance("carol", "TOKA")]),
+        );
+        let mut opened = open(&commitment, &witness).unwrap();
+
+        opened.debit("alice", "TOKA", 100).unwrap();
+        opened.credit("carol", "TOKA", 100);
+        let (next, changes) = close(&commitment, &opened, &witness).unwrap();
+
+        let applied = apply(&state, &changes).unwrap();
+        state.debit("alice", "TOKA", 100).unwrap();
+        state.credit("carol", "TOKA", 100);
+
+        assert_eq!(next, commit(&state));
+        assert_eq!(commit(&applied), next);
+        assert_eq!(
+            changes,
+            vec![
+                (balance("alice", "TOKA"), None),
+                (balance("carol", "TOKA"), Some(LeafValue::Balance(100))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{commitment::LeafKey, output};
+
+/// Why the orderbook contract rejected an action.
+///
+/// A failed `execute` carries the error in `HyleOutput::program_outputs` as hex-encoded bincode,
+/// see [`OrderBookError::encode_output`] and [`OrderBookError::decode_output`].
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OrderBookError {
+    DecodeFailure { blob_index: usize },
+    MissingBlob { blob_index: usize },
+    UnsupportedErc20Action,
+    WrongRecipient { expected: String, actual: String },
+    WrongSender { expected: String, actual: String },
+    WrongToken { expected: String, actual: String },
+    WrongAmount { expected: u128, actual: u128 },
+    InsufficientBalance { identity: String, asset: String, required: u128, available: u128 },
+    UnknownMarket(String),
+    InvalidQuantity,
+    InvalidPrice,
+    Overflow,
+    OrderNotFound(u64),
+    NotOrderOwner { order_id: u64, identity: String },
+    ReservationShortfall { order_id: u64 },
+    InvalidTimeInForce,
+    PostOnlyWouldCross,
+    FillOrKillUnfilled { required: u128, available: u128 },
+    ClockGoesBack,
+    AlreadyExpired,
+    InvalidFeeSchedule,
+    InvalidMarketConfig,
+    MarketAlreadyListed(String),
+    MarketNotActive(String),
+    InvalidLotSize { quantity: u128, lot_size: u128 },
+    BelowMinOrderSize { quantity: u128, min_order_size: u128 },
+    NotAdmin { identity: String },
+    InvalidPrivateInput,
+    EmptyBatch,
+    /// The witness does not prove its leaves against the state commitment
+    InvalidWitness,
+    /// The action needs a leaf of the state the witness did not reveal
+    MissingWitness(LeafKey),
+    InvalidTrigger,
+    /// The last trade price already meets the trigger condition
+    TriggerAlreadyReached,
+    TriggerNotAmendable(u64),
+    InvalidIceberg,
+    /// An `InsertPrivateOrder` came without its order in the private input
+    MissingPrivateOrder,
+    /// The revealed order is not the one the action commits to
+    PrivateOrderMismatch,
+    /// Batch auction markets only take good-till-cancelled limit orders
+    InvalidAuctionOrder,
+    /// The order would cross a resting order of its owner in a batch auction market
+    CrossesOwnOrder(u64),
+    NotAuctionMarket(String),
+}
+
+pub type OrderBookResult<T> = Result<T, OrderBookError>;
+
+impl OrderBookError {
+    pub fn encode_output(&self) -> String {
+        output::encode_output(self)
+    }
+
+    pub fn decode_output(program_outputs: &[u8]) -> Option<Self> {
+        output::decode_output(program_outputs)
+    }
+
+    /// Whether the error lies in the private input the prover passed rather than in the action.
+    /// Anyone can prove a blob, so a proof of such a failure would let them fail other users' actions.
+    pub fn is_private_input_error(&self) -> bool {
+        matches!(
+            self,
+            OrderBookError::InvalidPrivateInput
+                | OrderBookError::InvalidWitness
+                | OrderBookError::MissingWitness(_)
+                | OrderBookError::MissingPrivateOrder
+                | OrderBookError::PrivateOrderMismatch
+        )
+    }
+}
+
+impl fmt::Display for OrderBookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderBookError::DecodeFailure { blob_index } => {
+                write!(f, "Could not decode blob {}", blob_index)
+            }
+            OrderBookError::MissingBlob { blob_index } => {
+                write!(f, "Transaction has no blob at index {}", blob_index)
+            }
+            OrderBookError::UnsupportedErc20Action => write!(f, "Wrong ERC20Action"),
+            OrderBookError::WrongRecipient { expected, actual } => write!(
+                f,
+                "Transfer recipient should be {} but was {}",
+                expected, actual
+            ),
+            OrderBookError::WrongSender { expected, actual } => write!(
+                f,
+                "Transfer sender should be {} but was {}",
+                expected, actual
+            ),
+            OrderBookError::WrongToken { expected, actual } => write!(
+                f,
+                "Transfer should be on {} but was on {}",
+                expected, actual
+            ),
+            OrderBookError::WrongAmount { expected, actual } => write!(
+                f,
+                "Transfer amount should be {} but was {}",
+                expected, actual
+            ),
+            OrderBookError::InsufficientBalance { identity, asset, required, available } => write!(
+                f,
+                "Insufficient balance for {} - {}: {} required, {} available",
+                identity, asset, required, available
+            ),
+            OrderBookError::UnknownMarket(market) => write!(f, "Market {} not found", market),
+            OrderBookError::InvalidQuantity => write!(f, "Quantity must be positive"),
+            OrderBookError::InvalidPrice => write!(f, "Order price must be at least one tick"),
+            OrderBookError::Overflow => write!(f, "Amount overflows"),
+            OrderBookError::OrderNotFound(order_id) => write!(f, "Order {} not found", order_id),
+            OrderBookError::NotOrderOwner { order_id, identity } => {
+                write!(f, "Order {} does not belong to {}", order_id, identity)
+            }
+            OrderBookError::ReservationShortfall { order_id } => {
+                write!(f, "Order {} did not reserve enough to settle", order_id)
+            }
+            OrderBookError::InvalidTimeInForce => {
+                write!(f, "Time in force is not supported for this kind of order")
+            }
+            OrderBookError::PostOnlyWouldCross => {
+                write!(f, "Post-only order would take liquidity")
+            }
+            OrderBookError::FillOrKillUnfilled { required, available } => write!(
+                f,
+                "Fill-or-kill order needs {} but only {} is available",
+                required, available
+            ),
+            OrderBookError::ClockGoesBack => write!(f, "Clock can't go back in time"),
+            OrderBookError::AlreadyExpired => write!(f, "Order expiry has already passed"),
+            OrderBookError::InvalidFeeSchedule => write!(f, "Fee schedule is out of bounds"),
+            OrderBookError::InvalidMarketConfig => write!(f, "Market configuration is invalid"),
+            OrderBookError::MarketAlreadyListed(market) => write!(f, "Market {} is already listed", market),
+            OrderBookError::MarketNotActive(market) => write!(f, "Market {} is not active", market),
+            OrderBookError::InvalidLotSize { quantity, lot_size } => write!(
+                f,
+                "Quantity {} is not a multiple of the lot size {}",
+                quantity, lot_size
+            ),
+            OrderBookError::BelowMinOrderSize { quantity, min_order_size } => write!(
+                f,
+                "Quantity {} is below the minimum order size {}",
+                quantity, min_order_size
+            ),
+            OrderBookError::NotAdmin { identity } => {
+                write!(f, "{} is not the orderbook admin", identity)
+            }
+            OrderBookError::InvalidPrivateInput => write!(f, "Could not decode the private input"),
+            OrderBookError::EmptyBatch => write!(f, "Batch has no sub-actions"),
+            OrderBookError::InvalidWitness => {
+                write!(f, "State witness does not match the state commitment")
+            }
+            OrderBookError::MissingWitness(key) => {
+                write!(f, "State witness does not reveal {:?}", key)
+            }
+            OrderBookError::InvalidTrigger => {
+                write!(f, "Trigger orders need a non-zero trigger price and can't be FOK or post-only")
+            }
+            OrderBookError::TriggerAlreadyReached => {
+                write!(f, "The last trade price has already reached the trigger price")
+            }
+            OrderBookError::TriggerNotAmendable(order_id) => {
+                write!(f, "Order {} is waiting on its trigger and can't be amended", order_id)
+            }
+            OrderBookError::InvalidIceberg => {
+                write!(f, "Iceberg orders must be resting limit orders with a non-zero peak below their quantity")
+            }
+            OrderBookError::MissingPrivateOrder => write!(f, "Private input does not reveal the committed order"),
+            OrderBookError::PrivateOrderMismatch => {
+                write!(f, "Revealed order does not match the order commitment")
+            }
+            OrderBookError::InvalidAuctionOrder => {
+                write!(f, "Batch auction markets only take good-till-cancelled limit orders")
+            }
+            OrderBookError::CrossesOwnOrder(order_id) => {
+                write!(f, "Order would cross order {} of the same owner in the auction", order_id)
+            }
+            OrderBookError::NotAuctionMarket(market) => {
+                write!(f, "Market {} matches continuously", market)
+            }
+        }
+    }
+}
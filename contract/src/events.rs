@@ -0,0 +1,143 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commitment::StateChanges, output, FeeSchedule, MarketConfig, MarketStatus, OrderKind, OrderType, Price, TimeInForce, TriggerKind,
+};
+
+/// What a successful action did, in the order it happened.
+///
+/// The events of an action are part of the program output of `execute`, see [`ActionOutput`], so
+/// indexers can rebuild the trade history from settled transactions.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OrderBookEvent {
+    Deposited {
+        actor: String,
+        token: String,
+        amount: u128,
+    },
+    Withdrawn {
+        actor: String,
+        token: String,
+        amount: u128,
+    },
+    MarketCreated {
+        market: String,
+        config: MarketConfig,
+    },
+    MarketStatusChanged {
+        market: String,
+        status: MarketStatus,
+    },
+    MarketFeesChanged {
+        market: String,
+        fees: FeeSchedule,
+    },
+    MarketDelisted {
+        market: String,
+    },
+    AdminTransferred {
+        previous: String,
+        new_admin: String,
+    },
+    OrderPlaced {
+        order_id: u64,
+        actor: String,
+        market: String,
+        order_type: OrderType,
+        order_kind: OrderKind,
+        time_in_force: TimeInForce,
+        price: Price,
+        /// Only the peak of an iceberg order
+        quantity: u128,
+    },
+    OrderFilled {
+        market: String,
+        maker_order_id: u64,
+        taker_order_id: u64,
+        maker: String,
+        taker: String,
+        taker_side: OrderType,
+        quantity: u128,
+        price: Price,
+        /// Quote-asset fee charged to the maker, negative for a rebate
+        maker_fee: i128,
+        /// Quote-asset fee charged to the taker
+        taker_fee: i128,
+    },
+    OrderAmended {
+        order_id: u64,
+        actor: String,
+        market: String,
+        price: Price,
+        /// Only the displayed part of an iceberg order
+        quantity: u128,
+    },
+    OrderCancelled {
+        order_id: u64,
+        actor: String,
+        market: String,
+        remaining_quantity: u128,
+    },
+    /// An order lost `cancelled_quantity` to self-trade prevention, for a market bid its unspent budget
+    SelfTradePrevented {
+        order_id: u64,
+        actor: String,
+        market: String,
+        cancelled_quantity: u128,
+        remaining_quantity: u128,
+    },
+    OrderExpired {
+        order_id: u64,
+        actor: String,
+        market: String,
+        remaining_quantity: u128,
+    },
+    /// A stop-loss or take-profit order is waiting for the last trade price to reach its trigger
+    TriggerOrderPlaced {
+        order_id: u64,
+        actor: String,
+        market: String,
+        order_type: OrderType,
+        order_kind: OrderKind,
+        trigger_kind: TriggerKind,
+        trigger_price: Price,
+        price: Price,
+        /// Only the peak of an iceberg order
+        quantity: u128,
+    },
+    /// A trade reached the trigger price, the order is placed right after this event
+    OrderTriggered {
+        order_id: u64,
+        actor: String,
+        market: String,
+        trigger_price: Price,
+    },
+    /// A batch auction ran, the fills it made following at `price`
+    AuctionCleared {
+        market: String,
+        /// `None` when no orders crossed
+        price: Option<Price>,
+        volume: u128,
+    },
+}
+
+/// Program output of a successful `execute`, see [`ActionOutput::encode`] and [`ActionOutput::decode`]
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct ActionOutput {
+    pub events: Vec<OrderBookEvent>,
+    /// Leaves of the state tree the action wrote, so that anyone can follow the full state from
+    /// settled transactions, see [`crate::commitment::apply`]. Written orders are carried whole,
+    /// the hidden part of an iceberg and the details of a private order included.
+    pub changes: StateChanges,
+}
+
+impl ActionOutput {
+    pub fn encode(&self) -> String {
+        output::encode_output(self)
+    }
+
+    pub fn decode(program_outputs: &[u8]) -> Option<Self> {
+        output::decode_output(program_outputs)
+    }
+}
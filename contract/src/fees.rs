@@ -0,0 +1,47 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{OrderBookError, OrderBookResult};
+
+/// Highest fee rate a market may charge, in basis points.
+///
+/// Limit bids reserve fee headroom at this rate rather than at their market's current rate, so
+/// what a resting bid holds never depends on fee changes made while it rests.
+pub const MAX_FEE_BPS: i32 = 100;
+
+const BPS_DENOMINATOR: u128 = 10_000;
+
+/// Maker and taker fee rates of a market, in basis points of the fill notional.
+///
+/// A negative maker rate is a rebate paid out of the taker fee of the same fill.
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub struct FeeSchedule {
+    pub maker_bps: i32,
+    pub taker_bps: i32,
+}
+
+impl FeeSchedule {
+    /// Taker fees are capped by [`MAX_FEE_BPS`] and always cover the maker rebate
+    pub fn is_valid(&self) -> bool {
+        self.taker_bps >= 0
+            && self.taker_bps <= MAX_FEE_BPS
+            && self.maker_bps <= self.taker_bps
+            && self.maker_bps >= -self.taker_bps
+    }
+}
+
+/// Fee owed on `notional` at `bps`, rounded towards zero; negative for a rebate
+pub fn fee_for(notional: u128, bps: i32) -> OrderBookResult<i128> {
+    let magnitude = notional
+        .checked_mul(bps.unsigned_abs() as u128)
+        .ok_or(OrderBookError::Overflow)?
+        / BPS_DENOMINATOR;
+    let magnitude = i128::try_from(magnitude).map_err(|_| OrderBookError::Overflow)?;
+    Ok(if bps < 0 { -magnitude } else { magnitude })
+}
+
+/// `notional` plus the fee headroom a bid reserves on top of it
+pub fn with_fee_headroom(notional: u128) -> OrderBookResult<u128> {
+    let headroom = fee_for(notional, MAX_FEE_BPS)? as u128;
+    notional.checked_add(headroom).ok_or(OrderBookError::Overflow)
+}
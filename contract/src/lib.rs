@@ -1,309 +1,2241 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use bincode::{Decode, Encode};
-use sdk::{erc20::{self, ERC20Action}, BlobIndex, ContractInput, ContractName, Digestable, HyleOutput, Identity, RunResult};
+use sdk::{erc20::{self, ERC20Action}, Blob, BlobData, BlobIndex, ContractInput, ContractName, Digestable, HyleOutput, Identity, RunResult};
 use serde::{Deserialize, Serialize};
-
+use sha2::{Digest, Sha256};
+use commitment::{LeafKey, StateCommitment, StateWitness};
+use settlement::BidReservation;
+
+pub mod commitment;
+mod error;
+pub mod events;
+pub mod fees;
+pub mod merkle;
+mod output;
+mod price;
+pub mod settlement;
+
+pub use error::{OrderBookError, OrderBookResult};
+pub use events::OrderBookEvent;
+pub use fees::FeeSchedule;
+pub use price::Price;
+
+/// Runs the guest on `contract_input`, aborting if the prover's private input is wrong so that
+/// no proof of it exists, see [`try_execute`]
 pub fn execute(contract_input: ContractInput) -> HyleOutput{
 
-    let (input, orderbook_action) = sdk::guest::init_raw::<OrderBookAction>(contract_input);
-    let orderbook_contract_name = input.blobs.get(input.index.0).unwrap().contract_name.clone();
+    match try_execute(contract_input){
+        Ok(output) => output,
+        Err(e) => panic!("Invalid private input: {}", e),
+    }
+
+}
+
+/// Runs the guest on `contract_input`. Only errors of the action are committed as a failed output,
+/// an error of the private input, see [`OrderBookError::is_private_input_error`], is returned
+/// instead, for the host to fix its input and try again.
+pub fn try_execute(contract_input: ContractInput) -> OrderBookResult<HyleOutput>{
+
+    let input = contract_input;
+    let commitment: StateCommitment = input.initial_state.clone().into();
+
+    // Only the leaves the witness reveals are loaded, the rest of the state stays behind the root
+    let private_input = parse_private_input(&input.private_blob)?;
+    let orderbook_state = commitment::open(&commitment, &private_input.witness)?;
 
-    let orderbook_state: OrderBookState = input.initial_state.clone().into();
+    let (res, next_commitment): (RunResult, StateCommitment) = match run(orderbook_state, &input, private_input.order){
+        Ok(orderbook_contract) => {
+            let (next_commitment, changes) = commitment::close(&commitment, &orderbook_contract.state, &private_input.witness)?;
+            (Ok(events::ActionOutput { events: orderbook_contract.events, changes }.encode()), next_commitment)
+        }
+        Err(e) if e.is_private_input_error() => return Err(e),
+        Err(e) => (Err(e.encode_output()), commitment),
+    };
+
+    Ok(sdk::utils::as_hyle_output(input, next_commitment, res))
+
+}
+
+/// Runs the orderbook action of `input` on `orderbook_state`, with the order a
+/// [`OrderBookAction::InsertPrivateOrder`] commits to, if any.
+///
+/// The guest runs it on the part of the state a witness revealed, the host on the full state to
+/// know what the action does before proving it.
+pub fn run(orderbook_state: OrderBookState, input: &ContractInput, private_order: Option<PrivateOrder>) -> OrderBookResult<OrderBookContract>{
+
+    let orderbook_contract_name = input.blobs.get(input.index.0).unwrap().contract_name.clone();
 
     let mut orderbook_contract = OrderBookContract::new(
         input.identity.clone(),
         orderbook_contract_name,
         orderbook_state,
     );
+    orderbook_contract.private_order = private_order;
 
-    let res = match orderbook_action{
+    let orderbook_action = parse_blob::<OrderBookAction>(input.blobs.as_slice(), &input.index)?;
+
+    orderbook_contract.authorize(&orderbook_action)?;
+    run_action(&mut orderbook_contract, orderbook_action, input)?;
+
+    Ok(orderbook_contract)
+
+}
+
+fn run_action(orderbook_contract: &mut OrderBookContract, orderbook_action: OrderBookAction, input: &ContractInput) -> OrderBookResult<()>{
+
+    match orderbook_action{
 
         OrderBookAction::DepositAsset{} => {
             let transfer_action =
-            sdk::utils::parse_blob::<ERC20Action>(input.blobs.as_slice(), &BlobIndex(1));
+            parse_blob::<ERC20Action>(input.blobs.as_slice(), &BlobIndex(1))?;
     
             let transfer_action_contract_name = input.blobs.get(1).unwrap().contract_name.clone();
     
             orderbook_contract.deposit_asset(transfer_action, transfer_action_contract_name)
         }
 
-        OrderBookAction::InsertOrder { order_asset, order_type, order_price, order_quantity } => {
-            let order = Order { order_actor: input.identity.clone(), order_type: order_type, order_price: order_price, order_quantity: order_quantity };
-            orderbook_contract.insert_order(order, ContractName(order_asset))
+        OrderBookAction::CreateMarket { config } => {
+            orderbook_contract.create_market(config)
         }
 
-    };
+        OrderBookAction::PauseMarket { market } => {
+            orderbook_contract.set_market_status(market, MarketStatus::Paused)
+        }
+
+        OrderBookAction::UnpauseMarket { market } => {
+            orderbook_contract.set_market_status(market, MarketStatus::Active)
+        }
+
+        OrderBookAction::SetMarketFees { market, fees } => {
+            orderbook_contract.set_market_fees(market, fees)
+        }
+
+        OrderBookAction::TransferAdmin { new_admin } => {
+            orderbook_contract.transfer_admin(new_admin)
+        }
+
+        OrderBookAction::DelistMarket { market } => {
+            orderbook_contract.delist_market(market)
+        }
 
-    sdk::utils::as_hyle_output(input, orderbook_contract.state, res)
+        OrderBookAction::AdvanceClock { clock } => {
+            orderbook_contract.advance_clock(clock)
+        }
+
+        OrderBookAction::InsertOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak } => {
+            let order = Order { order_id: orderbook_contract.state.next_order_id(), order_actor: input.identity.clone(), order_type: order_type, order_price: order_price, order_quantity: order_quantity, time_in_force, order_expiry, self_trade_prevention, iceberg: order_peak.map(Iceberg::new) };
+            orderbook_contract.insert_order(order, order_kind, order_market)
+        }
+
+        OrderBookAction::PlaceTriggerOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak, trigger_kind, trigger_price } => {
+            let order = Order { order_id: orderbook_contract.state.next_order_id(), order_actor: input.identity.clone(), order_type, order_price, order_quantity, time_in_force, order_expiry, self_trade_prevention, iceberg: order_peak.map(Iceberg::new) };
+            let trigger_order = TriggerOrder { order, order_kind, trigger_kind, trigger_price };
+            orderbook_contract.place_trigger_order(trigger_order, order_market)
+        }
+
+        OrderBookAction::InsertPrivateOrder { commitment } => {
+            // The revealed order only counts if it is the one the public blob committed to
+            let private_order = orderbook_contract.private_order.take().ok_or(OrderBookError::MissingPrivateOrder)?;
+            if private_order.commitment() != commitment{
+                return Err(OrderBookError::PrivateOrderMismatch);
+            }
+            run_action(orderbook_contract, private_order.into(), input)
+        }
+
+        OrderBookAction::CancelOrder { order_id } => {
+            orderbook_contract.cancel_order(order_id)
+        }
+
+        OrderBookAction::AmendOrder { order_id, new_price, new_quantity } => {
+            orderbook_contract.amend_order(order_id, new_price, new_quantity)
+        }
+
+        OrderBookAction::ClearAuction { market } => {
+            orderbook_contract.clear_auction(market)
+        }
+
+        OrderBookAction::Batch(sub_actions) => {
+            if sub_actions.is_empty(){
+                return Err(OrderBookError::EmptyBatch);
+            }
+
+            // The first failing sub-action fails the whole batch, so none of it is committed
+            for sub_action in sub_actions{
+                run_action(orderbook_contract, sub_action.into(), input)?;
+            }
+
+            Ok(())
+        }
+
+        OrderBookAction::Withdraw { token, amount } => {
+            // The payout is the ERC20 blob right after this one in the same transaction
+            let transfer_index = BlobIndex(input.index.0 + 1);
+
+            let transfer_action =
+            parse_blob::<ERC20Action>(input.blobs.as_slice(), &transfer_index)?;
+
+            let transfer_action_contract_name = input.blobs.get(transfer_index.0).unwrap().contract_name.clone();
+
+            orderbook_contract.withdraw(ContractName(token), amount, transfer_action, transfer_action_contract_name)
+        }
+
+    }
+
+}
+
+/// Decodes the blob at `index`, returning an error instead of panicking the guest on bad input
+fn parse_blob<T: Decode>(blobs: &[Blob], index: &BlobIndex) -> OrderBookResult<T>{
+    let blob = blobs.get(index.0).ok_or(OrderBookError::MissingBlob { blob_index: index.0 })?;
+    let (parameters, _) = bincode::decode_from_slice(&blob.data.0, bincode::config::standard())
+        .map_err(|_| OrderBookError::DecodeFailure { blob_index: index.0 })?;
+    Ok(parameters)
+}
 
+fn parse_private_input(private_blob: &BlobData) -> OrderBookResult<PrivateInput>{
+    let (private_input, _) = bincode::decode_from_slice(&private_blob.0, bincode::config::standard())
+        .map_err(|_| OrderBookError::InvalidPrivateInput)?;
+    Ok(private_input)
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
 pub enum OrderBookAction {
     DepositAsset{},
-    InsertOrder{order_asset: String, order_type: OrderType, order_price: f64, order_quantity: u128},
+    /// Lists the market trading `config.base_token` against `config.quote_token`, orders can't be placed in unlisted markets
+    CreateMarket{config: MarketConfig},
+    PauseMarket{market: String},
+    UnpauseMarket{market: String},
+    /// Resting bids keep the fee headroom they reserved, so fees can change under them
+    SetMarketFees{market: String, fees: FeeSchedule},
+    TransferAdmin{new_admin: String},
+    /// Cancels and refunds every order resting in the market, then removes it
+    DelistMarket{market: String},
+    /// Moves the clock orders expire by forward, see [`Clock`]
+    AdvanceClock{clock: Clock},
+    /// `order_market` names a listed market, see [`MarketConfig::market_name`].
+    /// For a market bid `order_price` is ignored and `order_quantity` is the quote-asset budget to spend.
+    /// With `order_peak` the order is an iceberg showing at most that much of its quantity at a time
+    InsertOrder{order_market: String, order_type: OrderType, order_kind: OrderKind, time_in_force: TimeInForce, order_price: Price, order_quantity: u128, order_expiry: Option<Expiry>, self_trade_prevention: SelfTradePrevention, order_peak: Option<u128>},
+    /// Keeps an order off the book until the market trades at `trigger_price`, then places it as
+    /// `InsertOrder` would. Its funds are reserved from the start and it can't be fill-or-kill or post-only
+    PlaceTriggerOrder{order_market: String, order_type: OrderType, order_kind: OrderKind, time_in_force: TimeInForce, order_price: Price, order_quantity: u128, order_expiry: Option<Expiry>, self_trade_prevention: SelfTradePrevention, order_peak: Option<u128>, trigger_kind: TriggerKind, trigger_price: Price},
+    /// Places an order as `InsertOrder` would, its details only being revealed in the private input so
+    /// they can't be read from the mempool before the proof settles. `commitment` is [`PrivateOrder::commitment`]
+    InsertPrivateOrder{commitment: merkle::Hash},
+    CancelOrder{order_id: u64},
+    /// Keeps time priority when only the quantity goes down, otherwise the order goes to the back of its price
+    AmendOrder{order_id: u64, new_price: Price, new_quantity: u128},
+    /// Trades the crossing orders of a batch auction market at a single price, anyone may run it
+    ClearAuction{market: String},
+    /// Paid out by the `TransferFrom` of the orderbook's tokens to the user in the next blob.
+    ///
+    /// Withdrawals can't succeed yet: the token contract only runs that transfer within an allowance
+    /// the orderbook gave, and a contract has no way to approve one or to transfer on its own behalf.
+    /// They are blocked until the SDK lets the token contract accept a payout from another contract.
+    Withdraw{token: String, amount: u128},
+    /// Runs every sub-action in order, all or nothing
+    Batch(Vec<SubAction>),
 }
 
+/// Order management step of an [`OrderBookAction::Batch`], see the action of the same name
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize)]
+pub enum SubAction {
+    InsertOrder{order_market: String, order_type: OrderType, order_kind: OrderKind, time_in_force: TimeInForce, order_price: Price, order_quantity: u128, order_expiry: Option<Expiry>, self_trade_prevention: SelfTradePrevention, order_peak: Option<u128>},
+    PlaceTriggerOrder{order_market: String, order_type: OrderType, order_kind: OrderKind, time_in_force: TimeInForce, order_price: Price, order_quantity: u128, order_expiry: Option<Expiry>, self_trade_prevention: SelfTradePrevention, order_peak: Option<u128>, trigger_kind: TriggerKind, trigger_price: Price},
+    CancelOrder{order_id: u64},
+    AmendOrder{order_id: u64, new_price: Price, new_quantity: u128},
+}
+
+impl From<SubAction> for OrderBookAction{
+    fn from(sub_action: SubAction) -> Self{
+        match sub_action{
+            SubAction::InsertOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak } => {
+                OrderBookAction::InsertOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak }
+            }
+            SubAction::PlaceTriggerOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak, trigger_kind, trigger_price } => {
+                OrderBookAction::PlaceTriggerOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak, trigger_kind, trigger_price }
+            }
+            SubAction::CancelOrder { order_id } => OrderBookAction::CancelOrder { order_id },
+            SubAction::AmendOrder { order_id, new_price, new_quantity } => OrderBookAction::AmendOrder { order_id, new_price, new_quantity },
+        }
+    }
+}
+
+/// An order placed with [`OrderBookAction::InsertPrivateOrder`], the fields being those of `InsertOrder`
 #[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PrivateOrder{
+    pub order_market: String,
+    pub order_type: OrderType,
+    pub order_kind: OrderKind,
+    pub time_in_force: TimeInForce,
+    pub order_price: Price,
+    pub order_quantity: u128,
+    pub order_expiry: Option<Expiry>,
+    pub self_trade_prevention: SelfTradePrevention,
+    pub order_peak: Option<u128>,
+    /// Random bytes, so the commitment can't be told apart by hashing guessed orders
+    pub salt: [u8; 32],
+}
+
+impl PrivateOrder{
+    /// Hash of the encoded order, published in place of the order itself
+    pub fn commitment(&self) -> merkle::Hash{
+        let encoded = bincode::encode_to_vec(self, bincode::config::standard()).expect("Failed to encode private order");
+        Sha256::digest(encoded).into()
+    }
+}
+
+impl From<PrivateOrder> for OrderBookAction{
+    fn from(private_order: PrivateOrder) -> Self{
+        let PrivateOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak, salt: _ } = private_order;
+        OrderBookAction::InsertOrder { order_market, order_type, order_kind, time_in_force, order_price, order_quantity, order_expiry, self_trade_prevention, order_peak }
+    }
+}
+
+impl OrderBookAction{
+    /// Whether only the contract admin may run the action
+    pub fn requires_admin(&self) -> bool{
+        matches!(
+            self,
+            OrderBookAction::CreateMarket { .. }
+                | OrderBookAction::PauseMarket { .. }
+                | OrderBookAction::UnpauseMarket { .. }
+                | OrderBookAction::SetMarketFees { .. }
+                | OrderBookAction::TransferAdmin { .. }
+                | OrderBookAction::DelistMarket { .. }
+                | OrderBookAction::AdvanceClock { .. }
+        )
+    }
+}
+
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum OrderType{
     Ask,
     Bid
 }
 
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum OrderKind{
+    /// Rests on the book at its price until filled or cancelled
+    Limit,
+    /// Takes whatever the book offers right away and never rests
+    Market,
+}
+
+/// What happens to the part of an order that does not fill on arrival
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum TimeInForce{
+    /// Good-til-cancelled: the remainder rests on the book
+    Gtc,
+    /// Immediate-or-cancel: the remainder is dropped and its reservation refunded
+    Ioc,
+    /// Fill-or-kill: the order is rejected unless it fills completely on arrival
+    Fok,
+    /// The order is rejected if it would take liquidity, otherwise it rests
+    PostOnly,
+}
+
 
 #[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Order{
+    pub order_id: u64,
     pub order_actor: Identity,
     pub order_type: OrderType,
-    pub order_price: f64,
+    pub order_price: Price,
     pub order_quantity: u128,
+    /// Kept with the order so an amended order is held to it again
+    pub time_in_force: TimeInForce,
+    /// Past this point the order no longer matches and its reservation is returned
+    pub order_expiry: Option<Expiry>,
+    /// Applied when the order, coming in, meets a resting order of the same identity
+    pub self_trade_prevention: SelfTradePrevention,
+    /// Set for an order that only shows part of its quantity while resting
+    pub iceberg: Option<Iceberg>,
+}
+
+/// Display of an iceberg order: only `displayed` of its quantity can be matched until that is used
+/// up, then the order goes to the back of its level showing a new peak out of its hidden reserve.
+///
+/// The reserve is only hidden from events. The state changes of the program output carry the whole
+/// order, see [`events::ActionOutput::changes`].
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Iceberg{
+    pub peak: u128,
+    /// What is left of the current peak
+    pub displayed: u128,
 }
 
+/// What happens instead of a fill when an incoming order meets a resting order of its own identity
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum SelfTradePrevention{
+    /// The rest of the incoming order is cancelled, the resting one stays
+    CancelNewest,
+    /// The resting order is cancelled and matching goes on
+    CancelOldest,
+    /// Both orders are cancelled
+    CancelBoth,
+    /// Both orders lose the smaller of their quantities, cancelling whichever reaches zero.
+    /// A market bid has no quantity to decrement and is cancelled as with `CancelNewest`
+    DecrementAndCancel,
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Expiry{
+    BlockHeight(u64),
+    Timestamp(u64),
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum TriggerKind{
+    /// A sell triggers once the price falls to the trigger price, a buy once it rises to it
+    StopLoss,
+    /// A sell triggers once the price rises to the trigger price, a buy once it falls to it
+    TakeProfit,
+}
+
+/// Side of its trigger price a trade must happen on for a trigger order to be placed
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TriggerCondition{
+    AtOrAbove,
+    AtOrBelow,
+}
+
+/// An order kept off the book until its market trades at its trigger price
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TriggerOrder{
+    /// Placed as is once triggered, keeping its id
+    pub order: Order,
+    pub order_kind: OrderKind,
+    pub trigger_kind: TriggerKind,
+    pub trigger_price: Price,
+}
+
+/// Block height and timestamp orders expire by.
+///
+/// The contract input carries no block context, and the prover could pass in any, so only the admin
+/// moves the clock with [`OrderBookAction::AdvanceClock`]. It never goes back in time, so expired
+/// orders can't be revived.
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub struct Clock{
+    pub block_height: u64,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// What the prover passes to the contract in the private blob, bincode-encoded
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Default)]
+pub struct PrivateInput{
+    /// Leaves of the committed state the action reads or writes
+    pub witness: StateWitness,
+    /// What an [`OrderBookAction::InsertPrivateOrder`] commits to
+    pub order: Option<PrivateOrder>,
+}
+
+/// Where a resting order lives, so it can be reached from its id alone
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OrderLocation{
+    pub market: String,
+    pub order_type: OrderType,
+    /// Price level the order rests in, or trigger price of a trigger order
+    pub price: Price,
+    /// Set for a trigger order, which waits outside the book
+    pub trigger: Option<TriggerCondition>,
+}
+
+/// A single match between an incoming order and a resting one, executed at the resting price
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fill{
+    pub bid_order_id: u64,
+    pub ask_order_id: u64,
+    pub bid_actor: Identity,
+    pub ask_actor: Identity,
+    pub quantity: u128,
+    pub price: Price,
+    /// Side of the incoming order, the resting one being the maker
+    pub taker_side: OrderType,
+    /// What the bid had set aside to pay for the fill
+    pub bid_reservation: BidReservation,
+}
+
+/// How a listed market trades, set by [`OrderBookAction::CreateMarket`]
+#[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MarketConfig{
+    /// Token contract traded in the market
+    pub base_token: String,
+    /// Token contract prices are paid in
+    pub quote_token: String,
+    /// Quote-asset value of one price tick
+    pub tick_size: u128,
+    /// Order quantities are whole multiples of it
+    pub lot_size: u128,
+    pub min_order_size: u128,
+    pub fees: FeeSchedule,
+    pub status: MarketStatus,
+    pub matching: MatchingMode,
+}
+
+/// How a market matches its orders, set once when it is listed
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum MatchingMode{
+    /// Incoming orders match the book as they arrive
+    Continuous,
+    /// Orders only queue in the book, which may cross, until [`OrderBookAction::ClearAuction`]
+    /// trades them at one price, so the order of the transactions in between doesn't matter.
+    /// The contract input carries no block context to schedule auctions by, so anyone may run one:
+    /// the clearing price only depends on the book, whoever asks for it.
+    BatchAuction,
+}
+
+#[derive(Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum MarketStatus{
+    /// Orders can be placed, amended and cancelled
+    Active,
+    /// Resting orders can only be cancelled
+    Paused,
+}
+
+/// Orders resting at one price, oldest first
+pub type PriceLevel = VecDeque<Order>;
+
+/// Trigger orders waiting on one trigger price, oldest first
+pub type TriggerQueue = VecDeque<TriggerOrder>;
+
 #[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize)]
 pub struct Market {
-    pub ask_orders: Vec<Order>,
-    pub bid_orders: Vec<Order>,
+    /// The best ask level is the lowest price
+    pub ask_levels: BTreeMap<Price, PriceLevel>,
+    /// The best bid level is the highest price
+    pub bid_levels: BTreeMap<Price, PriceLevel>,
+    pub config: MarketConfig,
+    /// Trigger orders waiting for a trade at or above their trigger price
+    pub triggers_above: BTreeMap<Price, TriggerQueue>,
+    /// Trigger orders waiting for a trade at or below their trigger price
+    pub triggers_below: BTreeMap<Price, TriggerQueue>,
+    /// Price of the latest fill
+    pub last_trade_price: Option<Price>,
 }
 
+/// Maps are ordered, so equal states encode the same whatever order they were filled in
 #[derive(Encode, Decode, Debug, Clone, Serialize, Deserialize)]
 pub struct OrderBookState{
-    pub base_asset: String,
-    pub markets: HashMap<String, Market>,
-    pub balances: HashMap<String, HashMap<String, u128>>,
+    /// Identity allowed to run the actions listing and governing markets
+    pub admin: String,
+    /// Identity every fill's fees are credited to, withdrawing them like any other balance
+    pub fee_collector: String,
+    /// Id handed out to the next inserted order
+    pub order_nonce: u64,
+    /// Latest clock the admin set, see [`Clock`]
+    pub clock: Clock,
+    /// Location of every order currently resting on the book
+    pub order_index: BTreeMap<u64, OrderLocation>,
+    pub markets: BTreeMap<String, Market>,
+    pub balances: BTreeMap<String, BTreeMap<String, u128>>,
+    /// Keys a state opened from its commitment was shown, `None` for the full state.
+    /// See [`commitment::open`]
+    pub revealed: Option<BTreeSet<LeafKey>>,
 }
 
 pub struct OrderBookContract{
     identity: Identity,
     contract_name: ContractName,
     pub state: OrderBookState,
+    /// Everything the current action did, returned as the program output
+    pub events: Vec<OrderBookEvent>,
+    /// Revealed order of the action, taken by the `InsertPrivateOrder` it belongs to
+    private_order: Option<PrivateOrder>,
 }
 
-impl OrderBookState{
-    pub fn new(base: String) -> Self{
-        OrderBookState{
-            markets: HashMap::new(),
-            balances: HashMap::new(),
-            base_asset: base,
+impl OrderType{
+    pub fn opposite(&self) -> OrderType{
+        match self{
+            OrderType::Ask => OrderType::Bid,
+            OrderType::Bid => OrderType::Ask,
         }
     }
-}
 
-impl Market{
+    /// Whether an order of this type limited to `limit` would take a counter-order at `price`
+    pub fn reaches(&self, price: Price, limit: Price) -> bool{
+        match self{
+            OrderType::Ask => price >= limit,
+            OrderType::Bid => price <= limit,
+        }
+    }
+}
 
-    pub fn reorder_ask(&mut self){
-        self.ask_orders.sort_by(|a, b| a.order_price.partial_cmp(&b.order_price).unwrap());
-    }   
+impl TriggerKind{
+    /// What an order of `order_type` with this kind of trigger waits for
+    pub fn condition(&self, order_type: &OrderType) -> TriggerCondition{
+        match (self, order_type){
+            (TriggerKind::StopLoss, OrderType::Ask) | (TriggerKind::TakeProfit, OrderType::Bid) => TriggerCondition::AtOrBelow,
+            (TriggerKind::StopLoss, OrderType::Bid) | (TriggerKind::TakeProfit, OrderType::Ask) => TriggerCondition::AtOrAbove,
+        }
+    }
+}
 
-    pub fn reorder_bid(&mut self){
-        self.bid_orders.sort_by(|a, b| b.order_price.partial_cmp(&a.order_price).unwrap());
+impl TriggerCondition{
+    /// Whether trading anywhere between `low` and `high` reaches `trigger_price`
+    pub fn is_met(&self, trigger_price: Price, low: Price, high: Price) -> bool{
+        match self{
+            TriggerCondition::AtOrAbove => high >= trigger_price,
+            TriggerCondition::AtOrBelow => low <= trigger_price,
+        }
     }
+}
 
+impl TriggerOrder{
+    pub fn condition(&self) -> TriggerCondition{
+        self.trigger_kind.condition(&self.order.order_type)
+    }
 }
 
+impl Order{
+    pub fn is_expired(&self, clock: &Clock) -> bool{
+        match self.order_expiry{
+            Some(Expiry::BlockHeight(height)) => clock.block_height >= height,
+            Some(Expiry::Timestamp(timestamp)) => clock.timestamp >= timestamp,
+            None => false,
+        }
+    }
 
+    /// Quantity a counter-order can match right now, all of it unless the order is an iceberg
+    pub fn displayed_quantity(&self) -> u128{
+        self.iceberg.as_ref().map_or(self.order_quantity, |iceberg| std::cmp::min(iceberg.displayed, self.order_quantity))
+    }
 
-impl OrderBookContract{
-    
-    pub fn new(identity: Identity, contract_name: ContractName, state: OrderBookState) -> Self{
-        OrderBookContract{
-            identity,
-            contract_name,
-            state: state,
+    /// Takes `quantity` off a resting order, out of its displayed peak
+    pub fn reduce(&mut self, quantity: u128){
+        self.order_quantity -= quantity;
+        if let Some(iceberg) = &mut self.iceberg{
+            iceberg.displayed = iceberg.displayed.saturating_sub(quantity);
         }
     }
 
-    pub fn deposit_asset(&mut self, erc20_action : erc20::ERC20Action, erc20_name: ContractName) -> RunResult{
-
-        let mut balance = 0;
+    /// Good-til-cancelled order of `actor` that cancels itself on meeting its own orders
+    #[doc(hidden)]
+    pub fn for_test(order_id: u64, actor: &str, order_type: OrderType, price: u64, quantity: u128) -> Self{
+        Order {
+            order_id,
+            order_actor: Identity(actor.to_string()),
+            order_type,
+            order_price: Price(price),
+            order_quantity: quantity,
+            time_in_force: TimeInForce::Gtc,
+            order_expiry: None,
+            self_trade_prevention: SelfTradePrevention::CancelNewest,
+            iceberg: None,
+        }
+    }
+}
 
-        match erc20_action{
+impl Iceberg{
+    pub fn new(peak: u128) -> Self{
+        Iceberg { peak, displayed: peak }
+    }
+}
 
-            erc20::ERC20Action::Transfer { recipient, amount } => {
+impl Fill{
+    pub fn as_event(&self, market: &str, maker_fee: i128, taker_fee: i128) -> OrderBookEvent{
+        let (maker_order_id, taker_order_id, maker, taker) = match self.taker_side{
+            OrderType::Bid => (self.ask_order_id, self.bid_order_id, &self.ask_actor, &self.bid_actor),
+            OrderType::Ask => (self.bid_order_id, self.ask_order_id, &self.bid_actor, &self.ask_actor),
+        };
+        OrderBookEvent::OrderFilled {
+            market: market.to_string(),
+            maker_order_id,
+            taker_order_id,
+            maker: maker.0.clone(),
+            taker: taker.0.clone(),
+            taker_side: self.taker_side.clone(),
+            quantity: self.quantity,
+            price: self.price,
+            maker_fee,
+            taker_fee,
+        }
+    }
+}
 
-                if recipient != self.contract_name.0{
-                    return Err(format!(
-                        "Transfer recipient should be {} but was {}",
-                        self.contract_name.0, &recipient
-                    ));
-                }
+impl OrderBookState{
+    pub fn new(fee_collector: String, admin: String) -> Self{
+        OrderBookState{
+            admin,
+            markets: BTreeMap::new(),
+            balances: BTreeMap::new(),
+            fee_collector,
+            order_nonce: 0,
+            clock: Clock::default(),
+            order_index: BTreeMap::new(),
+            revealed: None,
+        }
+    }
 
-                balance = amount;
+    /// Fails when this is a partial state that was not shown `key`
+    pub fn check_revealed(&self, key: LeafKey) -> OrderBookResult<()>{
+        match &self.revealed{
+            Some(revealed) if !revealed.contains(&key) => Err(OrderBookError::MissingWitness(key)),
+            _ => Ok(()),
+        }
+    }
 
+    pub fn market(&self, market_name: &str) -> OrderBookResult<&Market>{
+        match self.markets.get(market_name){
+            Some(market) => Ok(market),
+            None => {
+                self.check_revealed(LeafKey::Market(market_name.to_string()))?;
+                Err(OrderBookError::UnknownMarket(market_name.to_string()))
             }
+        }
+    }
 
-            els => {
-                return Err(format!(
-                    "Wrong ERC20Action"
-                ));
-            }
+    pub fn market_mut(&mut self, market_name: &str) -> OrderBookResult<&mut Market>{
+        self.market(market_name)?;
+        Ok(self.markets.get_mut(market_name).unwrap())
+    }
 
+    pub fn order_location(&self, order_id: u64) -> OrderBookResult<Option<&OrderLocation>>{
+        match self.order_index.get(&order_id){
+            Some(location) => Ok(Some(location)),
+            None => {
+                self.check_revealed(LeafKey::Order(order_id))?;
+                Ok(None)
+            }
         }
+    }
+
+    /// Drops `order_id` from the order index
+    pub fn unindex_order(&mut self, order_id: u64) -> OrderBookResult<()>{
+        self.check_revealed(LeafKey::Order(order_id))?;
+        self.order_index.remove(&order_id);
+        Ok(())
+    }
 
-        let program_outputs = format!("Deposit success for {:?} - {:?}", self.identity.clone(), erc20_name.clone());
+    pub fn balance(&self, identity: &str, asset: &str) -> u128{
+        self.balances.get(identity).and_then(|b| b.get(asset)).copied().unwrap_or(0)
+    }
+
+    pub fn debit(&mut self, identity: &str, asset: &str, amount: u128) -> OrderBookResult<()>{
+        self.check_revealed(LeafKey::Balance { identity: identity.to_string(), asset: asset.to_string() })?;
+        let available = self.balance(identity, asset);
+        if available < amount{
+            return Err(OrderBookError::InsufficientBalance {
+                identity: identity.to_string(),
+                asset: asset.to_string(),
+                required: amount,
+                available,
+            });
+        }
+        *self.balances.entry(identity.to_string()).or_default().entry(asset.to_string()).or_insert(0) -= amount;
+        Ok(())
+    }
 
-        self.state.balances.entry(self.identity.0.clone()).or_insert(HashMap::new()).entry(erc20_name.0.clone()).and_modify(|e| *e += balance).or_insert(balance);
+    pub fn credit(&mut self, identity: &str, asset: &str, amount: u128){
+        *self.balances.entry(identity.to_string()).or_default().entry(asset.to_string()).or_insert(0) += amount;
+    }
 
-        Ok(program_outputs)
+    pub fn get_order(&self, order_id: u64) -> OrderBookResult<Option<&Order>>{
+        let Some(location) = self.order_location(order_id)? else { return Ok(None) };
+        self.market(&location.market)?.get_order(order_id, &location.order_type, location.price)
+    }
 
+    pub fn next_order_id(&mut self) -> u64{
+        let order_id = self.order_nonce;
+        self.order_nonce += 1;
+        order_id
     }
+}
 
-    pub fn insert_order(&mut self, order: Order, market_name: ContractName) -> RunResult{
+impl MarketConfig{
+    /// Name the market is listed under, e.g. `TOKA/USDC`
+    pub fn market_name(&self) -> String{
+        format!("{}/{}", self.base_token, self.quote_token)
+    }
 
-        let market = self.state.markets.entry(market_name.0.clone()).or_insert(Market{ask_orders: Vec::new(), bid_orders: Vec::new()});
+    pub fn is_valid(&self) -> bool{
+        self.base_token != self.quote_token && self.tick_size > 0 && self.lot_size > 0 && self.fees.is_valid()
+    }
 
+    /// Asset and amount still held for the unfilled part of a resting order
+    pub fn reservation(&self, order: &Order) -> OrderBookResult<(String, u128)>{
         match order.order_type{
             OrderType::Bid => {
-
-                if(self.state.balances.get(&self.identity.0.clone()).unwrap().get(&self.state.base_asset).unwrap() < &(order.order_price.round() as u128 * order.order_quantity)){
-                    return Err(format!(
-                        "Insufficient balance for {:?} - {:?}", self.identity.clone(), market_name.clone()
-                    ));
-                }else{
-                    let amount = order.order_price.round() as u128 * order.order_quantity;
-                    self.state.balances.entry(self.identity.0.clone()).or_insert(HashMap::new()).entry(self.state.base_asset.clone()).and_modify(|e| *e -= amount).or_insert(0);
-                }
-
-                match process_order(&mut order.clone(), market){
-                    Some((bid_actor, ask_actor, matched_quantity, matched_price)) => {
-                        let matched_amount = matched_quantity as f64 * matched_price;
-                        self.state.balances.entry(bid_actor.0.clone()).or_insert(HashMap::new()).entry(self.state.base_asset.clone()).and_modify(|e| *e += matched_amount as u128).or_insert(0);
-                        self.state.balances.entry(ask_actor.0.clone()).or_insert(HashMap::new()).entry(market_name.0.clone()).and_modify(|e| *e += matched_quantity).or_insert(0);
-                    },
-                    None => {}
-                };
-                
+                let amount = settlement::bid_reservation(order.order_price, order.order_quantity, self.tick_size)?;
+                Ok((self.quote_token.clone(), amount))
             }
-            OrderType::Ask => {
+            OrderType::Ask => Ok((self.base_token.clone(), order.order_quantity)),
+        }
+    }
 
+    /// Asset and amount an order of `order_kind` holds once placed
+    pub fn order_reservation(&self, order: &Order, order_kind: &OrderKind) -> OrderBookResult<(String, u128)>{
+        match (&order.order_type, order_kind){
+            // A market bid's quantity is the quote-asset budget it may spend, fees included
+            (OrderType::Bid, OrderKind::Market) => Ok((self.quote_token.clone(), order.order_quantity)),
+            // A limit bid also holds the most fees it could be charged, see [`fees::MAX_FEE_BPS`]
+            _ => self.reservation(order),
+        }
+    }
 
-                if(self.state.balances.get(&self.identity.0.clone()).unwrap().get(&market_name.0.clone()).unwrap() < &order.order_quantity){
-                    return Err(format!(
-                        "Insufficient balance for {:?} - {:?}", self.identity.clone(), market_name.clone()
-                    ));
-                }else{
-                    self.state.balances.entry(self.identity.0.clone()).or_insert(HashMap::new()).entry(market_name.0.clone()).and_modify(|e| *e -= order.order_quantity).or_insert(0);
-                }
+    /// Checks an order quantity against the lot size and minimum order size
+    pub fn check_quantity(&self, quantity: u128) -> OrderBookResult<()>{
+        if quantity % self.lot_size != 0{
+            return Err(OrderBookError::InvalidLotSize { quantity, lot_size: self.lot_size });
+        }
+        if quantity < self.min_order_size{
+            return Err(OrderBookError::BelowMinOrderSize { quantity, min_order_size: self.min_order_size });
+        }
+        Ok(())
+    }
 
-                match process_order(&mut order.clone(), market){
-                    Some((bid_actor, ask_actor, matched_quantity, matched_price)) => {
-                        let matched_amount = matched_quantity as f64 * matched_price;
-                        self.state.balances.entry(bid_actor.0.clone()).or_insert(HashMap::new()).entry(self.state.base_asset.clone()).and_modify(|e| *e += matched_amount as u128).or_insert(0);
-                        self.state.balances.entry(ask_actor.0.clone()).or_insert(HashMap::new()).entry(market_name.0.clone()).and_modify(|e| *e += matched_quantity).or_insert(0);
-                    },
-                    None => {}
-                };
-                
-            }
+    /// Active continuous market without fees, trading whole units at prices in whole quote units
+    #[doc(hidden)]
+    pub fn for_test(base_token: &str, quote_token: &str) -> Self{
+        MarketConfig {
+            base_token: base_token.to_string(),
+            quote_token: quote_token.to_string(),
+            tick_size: 1,
+            lot_size: 1,
+            min_order_size: 1,
+            fees: FeeSchedule::default(),
+            status: MarketStatus::Active,
+            matching: MatchingMode::Continuous,
         }
+    }
+}
 
-        let program_outputs = format!("Order inserted successfully for {:?} - {:?}", self.identity.clone(), market_name.clone());
+impl Market{
 
-        Ok(program_outputs)
+    pub fn new(config: MarketConfig) -> Self{
+        Market{
+            ask_levels: BTreeMap::new(),
+            bid_levels: BTreeMap::new(),
+            config,
+            triggers_above: BTreeMap::new(),
+            triggers_below: BTreeMap::new(),
+            last_trade_price: None,
+        }
+    }
 
+    fn levels(&self, order_type: &OrderType) -> &BTreeMap<Price, PriceLevel>{
+        match order_type{
+            OrderType::Ask => &self.ask_levels,
+            OrderType::Bid => &self.bid_levels,
+        }
     }
 
-}
+    fn levels_mut(&mut self, order_type: &OrderType) -> &mut BTreeMap<Price, PriceLevel>{
+        match order_type{
+            OrderType::Ask => &mut self.ask_levels,
+            OrderType::Bid => &mut self.bid_levels,
+        }
+    }
 
-fn process_order(order: &mut Order, market: &mut Market) -> Option<(Identity, Identity, u128, f64)> {
-    match order.order_type{
-        OrderType::Ask =>{
+    /// Levels of `order_type` from the best price down
+    pub fn levels_by_priority(&self, order_type: &OrderType) -> Box<dyn Iterator<Item = (&Price, &PriceLevel)> + '_>{
+        match order_type{
+            OrderType::Ask => Box::new(self.ask_levels.iter()),
+            OrderType::Bid => Box::new(self.bid_levels.iter().rev()),
+        }
+    }
 
-            // Finding if any order matches the current order
-            let mut bid_orders = market.clone().bid_orders;
-            let matched_order_index = bid_orders.iter_mut().position(|x| x.order_price >= order.order_price);
-            match matched_order_index{
-                // If any order has matched
-                Some(matched_index) => {
-                    let mut matched_order = market.bid_orders[matched_index].clone();
-                    let matched_quantity = std::cmp::min(order.order_quantity, matched_order.order_quantity);
-                    
-                    // Checking if the matched order has more quantity
-                    if matched_order.order_quantity > matched_quantity{
-                        matched_order.order_quantity -= matched_quantity;
-                        market.bid_orders[matched_index] = matched_order.clone();
-                    } else {
-                        market.bid_orders.remove(matched_index);
-                    }
+    /// The level of `order_type` at `price`, if any.
+    ///
+    /// Levels are removed once their last order goes, so an empty one is a level a partial state
+    /// was not shown, see [`commitment::open`].
+    pub fn level(&self, order_type: &OrderType, price: Price) -> OrderBookResult<Option<&PriceLevel>>{
+        revealed(self.levels(order_type).get(&price), || LeafKey::Level {
+            market: self.config.market_name(),
+            order_type: order_type.clone(),
+            price,
+        })
+    }
 
-                    // Checking if the new order has more quantity
-                    if order.order_quantity > matched_quantity{
-                        order.order_quantity -= matched_quantity;
-                        market.ask_orders.push(order.clone());
-                        market.reorder_ask();
-                    }
+    /// Resting orders of `order_type` in price-time priority
+    pub fn orders(&self, order_type: &OrderType) -> Box<dyn Iterator<Item = &Order> + '_>{
+        Box::new(self.levels_by_priority(order_type).flat_map(|(_, level)| level))
+    }
 
-                    // Returning bid_actor, ask_actor, matched_quantity, matched_price
-                    Some((matched_order.order_actor.clone(), order.order_actor.clone(), matched_quantity, matched_order.order_price))
-                }
-                // If no order has matched
-                None => {
-                    market.ask_orders.push(order.clone());
-                    market.reorder_ask();
-                    None
-                }
+    /// Every resting order, bids first
+    pub fn all_orders(&self) -> OrderBookResult<Vec<Order>>{
+        let mut orders = Vec::new();
+        for order_type in [OrderType::Bid, OrderType::Ask]{
+            for price in self.levels_by_priority(&order_type).map(|(price, _)| *price){
+                orders.extend(self.level(&order_type, price)?.into_iter().flatten().cloned());
             }
         }
+        Ok(orders)
+    }
 
-        OrderType::Bid => {
-        
-            // Finding if any order matches the current order
-            let mut ask_orders = market.clone().ask_orders;
-            let matched_order_index = ask_orders.iter_mut().position(|x| x.order_price <= order.order_price);
-            match matched_order_index{
-
-                Some(matched_index) => {
-                    let mut matched_order = market.ask_orders[matched_index].clone();
-                    let matched_quantity = std::cmp::min(order.order_quantity, matched_order.order_quantity);
-                    
-                    // Checking if the matched order has more quantity
-                    if matched_order.order_quantity > matched_quantity{
-                        matched_order.order_quantity -= matched_quantity;
-                        market.ask_orders[matched_index] = matched_order.clone();
-                    }else{
-                        market.ask_orders.remove(matched_index);
-                    }
+    /// Whether an order of `order_type` at `price` would match the best live counter-order
+    pub fn crosses(&self, order_type: &OrderType, price: Price, clock: &Clock) -> OrderBookResult<bool>{
+        let side = order_type.opposite();
+        for level_price in self.levels_by_priority(&side).map(|(level_price, _)| *level_price){
+            if !order_type.reaches(level_price, price){
+                break;
+            }
+            if self.level(&side, level_price)?.into_iter().flatten().any(|o| !o.is_expired(clock)){
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 
-                    // Checking if the new order has more quantity
-                    if order.order_quantity > matched_quantity{
-                        order.order_quantity -= matched_quantity;
-                        market.bid_orders.push(order.clone());
-                        market.reorder_bid();
+    /// Quantity of `order` that matching it right now without going past `limit` would use up,
+    /// either filled or cancelled against its owner's orders as its self-trade prevention does
+    pub fn fillable_quantity(&self, order: &Order, limit: Option<Price>, clock: &Clock) -> OrderBookResult<u128>{
+        let side = order.order_type.opposite();
+        let mut quantity = 0;
+        for level_price in self.levels_by_priority(&side).map(|(level_price, _)| *level_price){
+            if limit.is_some_and(|limit| !order.order_type.reaches(level_price, limit)){
+                break;
+            }
+            for o in self.level(&side, level_price)?.into_iter().flatten().filter(|o| !o.is_expired(clock)){
+                if o.order_actor == order.order_actor{
+                    match order.self_trade_prevention{
+                        SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth => return Ok(quantity),
+                        SelfTradePrevention::CancelOldest => continue,
+                        // The own order takes off as much of the incoming one as a fill would
+                        SelfTradePrevention::DecrementAndCancel => {}
                     }
-
-                    // Returning bid_actor, ask_actor, matched_quantity, matched_price
-                    Some((order.order_actor.clone(), matched_order.order_actor.clone(), matched_quantity, matched_order.order_price))
                 }
+                quantity += o.order_quantity;
+            }
+        }
+        Ok(quantity)
+    }
 
-                None => {
-                    market.bid_orders.push(order.clone());
-                    market.reorder_bid();
-                    None
-                }
+    /// First order of the owner of `order` that `order` would trade with, whatever its expiry
+    pub fn crossed_own_order(&self, order: &Order) -> OrderBookResult<Option<u64>>{
+        let side = order.order_type.opposite();
+        for level_price in self.levels_by_priority(&side).map(|(level_price, _)| *level_price){
+            if !order.order_type.reaches(level_price, order.order_price){
+                break;
+            }
+            if let Some(own) = self.level(&side, level_price)?.into_iter().flatten().find(|o| o.order_actor == order.order_actor){
+                return Ok(Some(own.order_id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Prices of the levels of `order_type` that reach the best counter-order, best first
+    fn crossing_prices(&self, order_type: &OrderType) -> Vec<Price>{
+        let Some(best_counter) = self.levels_by_priority(&order_type.opposite()).next().map(|(price, _)| *price) else { return Vec::new() };
+        self.levels_by_priority(order_type).map(|(price, _)| *price).take_while(|price| order_type.reaches(best_counter, *price)).collect()
+    }
 
+    /// Price an auction clears the book at and the quantity it trades there, if any.
+    ///
+    /// The price trades the most, then leaves the least unfilled on the heavier side, the lower
+    /// middle one of the prices still tied being taken. Expired orders don't count.
+    pub fn auction_price(&self, clock: &Clock) -> OrderBookResult<Option<(Price, u128)>>{
+        let mut depth = Vec::new();
+        for order_type in [OrderType::Bid, OrderType::Ask]{
+            for price in self.crossing_prices(&order_type){
+                let quantity: u128 = self.level(&order_type, price)?.into_iter().flatten().filter(|o| !o.is_expired(clock)).map(|o| o.order_quantity).sum();
+                depth.push((order_type.clone(), price, quantity));
             }
+        }
+
+        // Volume and imbalance at `price`, bids buying at or below their price and asks selling at or above theirs
+        let outcome = |price: Price| -> (u128, u128){
+            let demand: u128 = depth.iter().filter(|(t, p, _)| *t == OrderType::Bid && *p >= price).map(|(_, _, q)| q).sum();
+            let supply: u128 = depth.iter().filter(|(t, p, _)| *t == OrderType::Ask && *p <= price).map(|(_, _, q)| q).sum();
+            (std::cmp::min(demand, supply), demand.abs_diff(supply))
+        };
+
+        let mut prices: Vec<Price> = depth.iter().map(|(_, price, _)| *price).collect();
+        prices.sort();
+        prices.dedup();
+
+        let Some(best) = prices.iter().map(|price| outcome(*price)).filter(|(volume, _)| *volume > 0).max_by_key(|(volume, imbalance)| (*volume, std::cmp::Reverse(*imbalance))) else {
+            return Ok(None);
+        };
+        let tied: Vec<Price> = prices.into_iter().filter(|price| outcome(*price) == best).collect();
+        Ok(Some((tied[(tied.len() - 1) / 2], best.0)))
+    }
 
+    /// Puts `order` behind every order already resting at its price, an iceberg showing a full peak
+    pub fn rest_order(&mut self, mut order: Order) -> OrderBookResult<()>{
+        self.level(&order.order_type, order.order_price)?;
+        if let Some(iceberg) = &mut order.iceberg{
+            iceberg.displayed = std::cmp::min(iceberg.peak, order.order_quantity);
         }
+        self.levels_mut(&order.order_type).entry(order.order_price).or_default().push_back(order);
+        Ok(())
     }
 
-}
+    /// Next resting order of `order_type` to match
+    pub fn best_mut(&mut self, order_type: &OrderType) -> OrderBookResult<Option<&mut Order>>{
+        let Some(price) = self.levels_by_priority(order_type).next().map(|(price, _)| *price) else { return Ok(None) };
+        self.level(order_type, price)?;
+        Ok(self.levels_mut(order_type).get_mut(&price).unwrap().front_mut())
+    }
 
-impl Digestable for OrderBookState{
+    /// Takes the best order of `order_type` off the book once it is used up, and sends an iceberg
+    /// whose peak is used up to the back of its level with a new one, losing its time priority
+    pub fn refresh_best(&mut self, order_type: &OrderType) -> OrderBookResult<()>{
+        let Some(best) = self.best_mut(order_type)? else { return Ok(()) };
+        if best.order_quantity == 0{
+            self.pop_best(order_type);
+        }else if best.displayed_quantity() == 0{
+            let order = self.pop_best(order_type).unwrap();
+            self.rest_order(order)?;
+        }
+        Ok(())
+    }
 
-    fn as_digest(&self) -> sdk::StateDigest {
-        sdk::StateDigest(
-            bincode::encode_to_vec(self, bincode::config::standard())
-                .expect("Failed to encode TicketAppState"),
-        )
+    /// Takes the next resting order of `order_type` to match off the book
+    pub fn pop_best(&mut self, order_type: &OrderType) -> Option<Order>{
+        let mut level = match order_type{
+            OrderType::Ask => self.ask_levels.first_entry()?,
+            OrderType::Bid => self.bid_levels.last_entry()?,
+        };
+        let order = level.get_mut().pop_front();
+        if level.get().is_empty(){
+            level.remove();
+        }
+        order
     }
 
-}
+    pub fn get_order(&self, order_id: u64, order_type: &OrderType, price: Price) -> OrderBookResult<Option<&Order>>{
+        Ok(self.level(order_type, price)?.and_then(|level| level.iter().find(|o| o.order_id == order_id)))
+    }
 
-impl From<sdk::StateDigest> for OrderBookState {
-    fn from(state: sdk::StateDigest) -> Self {
-        let (orderbook_state, _) =
-            bincode::decode_from_slice(&state.0, bincode::config::standard())
-                .expect("Could not decode OrderbookState");
-        orderbook_state
+    pub fn get_order_mut(&mut self, order_id: u64, order_type: &OrderType, price: Price) -> OrderBookResult<Option<&mut Order>>{
+        self.level(order_type, price)?;
+        Ok(self.levels_mut(order_type).get_mut(&price).and_then(|level| level.iter_mut().find(|o| o.order_id == order_id)))
     }
-}
\ No newline at end of file
+
+    pub fn remove_order(&mut self, order_id: u64, order_type: &OrderType, price: Price) -> OrderBookResult<Option<Order>>{
+        self.level(order_type, price)?;
+        let levels = self.levels_mut(order_type);
+        let Some(level) = levels.get_mut(&price) else { return Ok(None) };
+        let Some(index) = level.iter().position(|o| o.order_id == order_id) else { return Ok(None) };
+        let order = level.remove(index);
+        if level.is_empty(){
+            levels.remove(&price);
+        }
+        Ok(order)
+    }
+
+    fn trigger_queues(&self, condition: &TriggerCondition) -> &BTreeMap<Price, TriggerQueue>{
+        match condition{
+            TriggerCondition::AtOrAbove => &self.triggers_above,
+            TriggerCondition::AtOrBelow => &self.triggers_below,
+        }
+    }
+
+    fn trigger_queues_mut(&mut self, condition: &TriggerCondition) -> &mut BTreeMap<Price, TriggerQueue>{
+        match condition{
+            TriggerCondition::AtOrAbove => &mut self.triggers_above,
+            TriggerCondition::AtOrBelow => &mut self.triggers_below,
+        }
+    }
+
+    /// Trigger orders waiting on `trigger_price`, an empty queue being hidden as with [`Market::level`]
+    pub fn trigger_queue(&self, condition: &TriggerCondition, trigger_price: Price) -> OrderBookResult<Option<&TriggerQueue>>{
+        revealed(self.trigger_queues(condition).get(&trigger_price), || LeafKey::Triggers {
+            market: self.config.market_name(),
+            condition: *condition,
+            price: trigger_price,
+        })
+    }
+
+    /// Every trigger order, those waiting for a rise first
+    pub fn all_triggers(&self) -> OrderBookResult<Vec<TriggerOrder>>{
+        let mut trigger_orders = Vec::new();
+        for condition in [TriggerCondition::AtOrAbove, TriggerCondition::AtOrBelow]{
+            for trigger_price in self.trigger_queues(&condition).keys(){
+                trigger_orders.extend(self.trigger_queue(&condition, *trigger_price)?.into_iter().flatten().cloned());
+            }
+        }
+        Ok(trigger_orders)
+    }
+
+    /// Queues `trigger_order` behind those already waiting on its trigger price
+    pub fn add_trigger(&mut self, trigger_order: TriggerOrder) -> OrderBookResult<()>{
+        let condition = trigger_order.condition();
+        self.trigger_queue(&condition, trigger_order.trigger_price)?;
+        self.trigger_queues_mut(&condition).entry(trigger_order.trigger_price).or_default().push_back(trigger_order);
+        Ok(())
+    }
+
+    pub fn get_trigger(&self, order_id: u64, condition: &TriggerCondition, trigger_price: Price) -> OrderBookResult<Option<&TriggerOrder>>{
+        Ok(self.trigger_queue(condition, trigger_price)?.and_then(|queue| queue.iter().find(|t| t.order.order_id == order_id)))
+    }
+
+    pub fn remove_trigger(&mut self, order_id: u64, condition: &TriggerCondition, trigger_price: Price) -> OrderBookResult<Option<TriggerOrder>>{
+        self.trigger_queue(condition, trigger_price)?;
+        let queues = self.trigger_queues_mut(condition);
+        let Some(queue) = queues.get_mut(&trigger_price) else { return Ok(None) };
+        let Some(index) = queue.iter().position(|t| t.order.order_id == order_id) else { return Ok(None) };
+        let trigger_order = queue.remove(index);
+        if queue.is_empty(){
+            queues.remove(&trigger_price);
+        }
+        Ok(trigger_order)
+    }
+
+    /// Takes off the next trigger order that trading between `low` and `high` reached, the ones
+    /// waiting for a rise first and the trigger prices nearest the trades first
+    pub fn pop_triggered(&mut self, low: Price, high: Price) -> OrderBookResult<Option<TriggerOrder>>{
+        let (condition, trigger_price) = match (self.triggers_above.keys().next(), self.triggers_below.keys().next_back()){
+            (Some(price), _) if TriggerCondition::AtOrAbove.is_met(*price, low, high) => (TriggerCondition::AtOrAbove, *price),
+            (_, Some(price)) if TriggerCondition::AtOrBelow.is_met(*price, low, high) => (TriggerCondition::AtOrBelow, *price),
+            _ => return Ok(None),
+        };
+        self.trigger_queue(&condition, trigger_price)?;
+        let queues = self.trigger_queues_mut(&condition);
+        let queue = queues.get_mut(&trigger_price).unwrap();
+        let trigger_order = queue.pop_front();
+        if queue.is_empty(){
+            queues.remove(&trigger_price);
+        }
+        Ok(trigger_order)
+    }
+
+}
+
+/// Passes through a level or trigger queue, failing on an empty one as those only stand for
+/// what a partial state was not shown, see [`commitment::open`]
+fn revealed<T>(queue: Option<&VecDeque<T>>, key: impl FnOnce() -> LeafKey) -> OrderBookResult<Option<&VecDeque<T>>>{
+    match queue{
+        Some(queue) if queue.is_empty() => Err(OrderBookError::MissingWitness(key())),
+        queue => Ok(queue),
+    }
+}
+
+
+
+impl OrderBookContract{
+    
+    pub fn new(identity: Identity, contract_name: ContractName, state: OrderBookState) -> Self{
+        OrderBookContract{
+            identity,
+            contract_name,
+            state: state,
+            events: Vec::new(),
+            private_order: None,
+        }
+    }
+
+    /// Orders expire lazily, when matching meets them, so moving the clock touches no order
+    pub fn advance_clock(&mut self, clock: Clock) -> OrderBookResult<()>{
+
+        if clock.block_height < self.state.clock.block_height || clock.timestamp < self.state.clock.timestamp{
+            return Err(OrderBookError::ClockGoesBack);
+        }
+
+        self.state.clock = clock;
+
+        Ok(())
+
+    }
+
+    pub fn deposit_asset(&mut self, erc20_action : erc20::ERC20Action, erc20_name: ContractName) -> OrderBookResult<()>{
+
+        let balance = match erc20_action{
+
+            erc20::ERC20Action::Transfer { recipient, amount } => {
+
+                if recipient != self.contract_name.0{
+                    return Err(OrderBookError::WrongRecipient {
+                        expected: self.contract_name.0.clone(),
+                        actual: recipient,
+                    });
+                }
+
+                amount
+
+            }
+
+            _ => {
+                return Err(OrderBookError::UnsupportedErc20Action);
+            }
+
+        };
+
+        self.state.credit(&self.identity.0, &erc20_name.0, balance);
+
+        self.events.push(OrderBookEvent::Deposited { actor: self.identity.0.clone(), token: erc20_name.0.clone(), amount: balance });
+
+        Ok(())
+
+    }
+
+    pub fn withdraw(&mut self, token: ContractName, amount: u128, erc20_action: erc20::ERC20Action, erc20_name: ContractName) -> OrderBookResult<()>{
+
+        if erc20_name != token{
+            return Err(OrderBookError::WrongToken {
+                expected: token.0.clone(),
+                actual: erc20_name.0.clone(),
+            });
+        }
+
+        // A plain transfer would pay out of the user's own token account, the funds have to leave the orderbook's
+        match erc20_action{
+
+            erc20::ERC20Action::TransferFrom { sender, recipient, amount: transfer_amount } => {
+
+                if sender != self.contract_name.0{
+                    return Err(OrderBookError::WrongSender {
+                        expected: self.contract_name.0.clone(),
+                        actual: sender,
+                    });
+                }
+
+                if recipient != self.identity.0{
+                    return Err(OrderBookError::WrongRecipient {
+                        expected: self.identity.0.clone(),
+                        actual: recipient,
+                    });
+                }
+
+                if transfer_amount != amount{
+                    return Err(OrderBookError::WrongAmount {
+                        expected: amount,
+                        actual: transfer_amount,
+                    });
+                }
+
+            }
+
+            _ => {
+                return Err(OrderBookError::UnsupportedErc20Action);
+            }
+
+        }
+
+        self.state.debit(&self.identity.0, &token.0, amount)?;
+
+        self.events.push(OrderBookEvent::Withdrawn { actor: self.identity.0.clone(), token: token.0.clone(), amount });
+
+        Ok(())
+
+    }
+
+    /// Rejects admin actions sent by anyone but the admin
+    pub fn authorize(&self, action: &OrderBookAction) -> OrderBookResult<()>{
+        if action.requires_admin() && self.identity.0 != self.state.admin{
+            return Err(OrderBookError::NotAdmin { identity: self.identity.0.clone() });
+        }
+        Ok(())
+    }
+
+    pub fn create_market(&mut self, config: MarketConfig) -> OrderBookResult<()>{
+
+        if !config.is_valid(){
+            return Err(OrderBookError::InvalidMarketConfig);
+        }
+
+        let market_name = config.market_name();
+        match self.state.market(&market_name){
+            Ok(_) => return Err(OrderBookError::MarketAlreadyListed(market_name)),
+            Err(OrderBookError::UnknownMarket(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        self.state.markets.insert(market_name.clone(), Market::new(config.clone()));
+
+        self.events.push(OrderBookEvent::MarketCreated { market: market_name, config });
+
+        Ok(())
+
+    }
+
+    pub fn set_market_status(&mut self, market_name: String, status: MarketStatus) -> OrderBookResult<()>{
+
+        let market = self.state.market_mut(&market_name)?;
+        market.config.status = status;
+
+        self.events.push(OrderBookEvent::MarketStatusChanged { market: market_name, status });
+
+        Ok(())
+
+    }
+
+    pub fn set_market_fees(&mut self, market_name: String, fees: FeeSchedule) -> OrderBookResult<()>{
+
+        if !fees.is_valid(){
+            return Err(OrderBookError::InvalidFeeSchedule);
+        }
+
+        let market = self.state.market_mut(&market_name)?;
+        market.config.fees = fees;
+
+        self.events.push(OrderBookEvent::MarketFeesChanged { market: market_name, fees });
+
+        Ok(())
+
+    }
+
+    pub fn transfer_admin(&mut self, new_admin: String) -> OrderBookResult<()>{
+
+        let previous = std::mem::replace(&mut self.state.admin, new_admin.clone());
+
+        self.events.push(OrderBookEvent::AdminTransferred { previous, new_admin });
+
+        Ok(())
+
+    }
+
+    pub fn delist_market(&mut self, market_name: String) -> OrderBookResult<()>{
+
+        let market = self.state.market(&market_name)?;
+        let resting = market.all_orders()?.into_iter().map(|order| (order, OrderKind::Limit));
+        let waiting = market.all_triggers()?.into_iter().map(|t| (t.order, t.order_kind));
+        let (config, orders): (MarketConfig, Vec<(Order, OrderKind)>) = (market.config.clone(), resting.chain(waiting).collect());
+        self.state.markets.remove(&market_name);
+
+        // Every resting or trigger order goes back to its owner as if they had cancelled it
+        for (order, order_kind) in orders{
+            self.state.unindex_order(order.order_id)?;
+
+            let (refund_asset, refund_amount) = config.order_reservation(&order, &order_kind)?;
+            self.state.credit(&order.order_actor.0, &refund_asset, refund_amount);
+
+            self.events.push(OrderBookEvent::OrderCancelled {
+                order_id: order.order_id,
+                actor: order.order_actor.0.clone(),
+                market: market_name.clone(),
+                remaining_quantity: order.order_quantity,
+            });
+        }
+
+        self.events.push(OrderBookEvent::MarketDelisted { market: market_name });
+
+        Ok(())
+
+    }
+
+    pub fn insert_order(&mut self, order: Order, order_kind: OrderKind, market_name: String) -> OrderBookResult<()>{
+
+        let traded = self.place_order(order, order_kind, &market_name)?;
+        self.fire_triggers(&market_name, traded)
+
+    }
+
+    pub fn place_trigger_order(&mut self, trigger_order: TriggerOrder, market_name: String) -> OrderBookResult<()>{
+
+        // The book a trigger order meets once placed is unknown, so it can't depend on filling or not
+        if trigger_order.trigger_price.0 == 0 || matches!(trigger_order.order.time_in_force, TimeInForce::Fok | TimeInForce::PostOnly){
+            return Err(OrderBookError::InvalidTrigger);
+        }
+
+        self.reserve_order(&trigger_order.order, &trigger_order.order_kind, &market_name)?;
+
+        let condition = trigger_order.condition();
+        let market = self.state.market_mut(&market_name)?;
+        if market.config.matching == MatchingMode::BatchAuction{
+            return Err(OrderBookError::InvalidAuctionOrder);
+        }
+        if market.last_trade_price.is_some_and(|price| condition.is_met(trigger_order.trigger_price, price, price)){
+            return Err(OrderBookError::TriggerAlreadyReached);
+        }
+        market.add_trigger(trigger_order.clone())?;
+
+        let TriggerOrder { order, order_kind, trigger_kind, trigger_price, .. } = trigger_order;
+        self.state.order_index.insert(order.order_id, OrderLocation { market: market_name.clone(), order_type: order.order_type.clone(), price: trigger_price, trigger: Some(condition) });
+
+        self.events.push(OrderBookEvent::TriggerOrderPlaced {
+            order_id: order.order_id,
+            actor: order.order_actor.0.clone(),
+            market: market_name,
+            order_type: order.order_type.clone(),
+            order_kind,
+            trigger_kind,
+            trigger_price,
+            price: order.order_price,
+            quantity: order.displayed_quantity(),
+        });
+
+        Ok(())
+
+    }
+
+    /// Checks `order` can be placed in the market and takes what it can cost from its owner's balance
+    fn reserve_order(&mut self, order: &Order, order_kind: &OrderKind, market_name: &str) -> OrderBookResult<MarketConfig>{
+
+        let time_in_force = &order.time_in_force;
+
+        if *order_kind == OrderKind::Limit && order.order_price.0 == 0 {
+            return Err(OrderBookError::InvalidPrice);
+        }
+        if order.order_quantity == 0 {
+            return Err(OrderBookError::InvalidQuantity);
+        }
+
+        // Market orders never rest, and a market bid's budget can't be checked for a complete fill
+        match (order_kind, time_in_force, &order.order_type){
+            (OrderKind::Market, TimeInForce::PostOnly, _) | (OrderKind::Market, TimeInForce::Fok, OrderType::Bid) => {
+                return Err(OrderBookError::InvalidTimeInForce);
+            }
+            _ => {}
+        }
+
+        let clock = self.state.clock;
+        if order.is_expired(&clock) {
+            return Err(OrderBookError::AlreadyExpired);
+        }
+
+        let market = self.state.market(market_name)?;
+        if market.config.status != MarketStatus::Active{
+            return Err(OrderBookError::MarketNotActive(market_name.to_string()));
+        }
+        // A market bid's quantity is a budget, its fills are kept to whole lots while matching
+        if (&order.order_type, order_kind) != (&OrderType::Bid, &OrderKind::Market){
+            market.config.check_quantity(order.order_quantity)?;
+        }
+        // Only an order that may rest has something to hide, and its peak must be a valid order on its own
+        if let Some(iceberg) = &order.iceberg{
+            if *order_kind != OrderKind::Limit || !matches!(time_in_force, TimeInForce::Gtc | TimeInForce::PostOnly) || iceberg.peak == 0 || iceberg.peak >= order.order_quantity{
+                return Err(OrderBookError::InvalidIceberg);
+            }
+            market.config.check_quantity(iceberg.peak)?;
+        }
+        // Orders of an auction market wait for the next clearing, so they have to rest at a price
+        if market.config.matching == MatchingMode::BatchAuction && (*order_kind != OrderKind::Limit || *time_in_force != TimeInForce::Gtc){
+            return Err(OrderBookError::InvalidAuctionOrder);
+        }
+        let config = market.config.clone();
+
+        let (reserved_asset, reserved_amount) = config.order_reservation(order, order_kind)?;
+        self.state.debit(&order.order_actor.0, &reserved_asset, reserved_amount)?;
+
+        Ok(config)
+
+    }
+
+    /// Places and matches `order`, returning the lowest and highest price it traded at
+    fn place_order(&mut self, order: Order, order_kind: OrderKind, market_name: &str) -> OrderBookResult<TradedRange>{
+
+        // Reserving what the order can cost before it touches the book
+        let config = self.reserve_order(&order, &order_kind, market_name)?;
+        let time_in_force = order.time_in_force.clone();
+        let clock = self.state.clock;
+
+        self.events.push(OrderBookEvent::OrderPlaced {
+            order_id: order.order_id,
+            actor: order.order_actor.0.clone(),
+            market: market_name.to_string(),
+            order_type: order.order_type.clone(),
+            order_kind: order_kind.clone(),
+            time_in_force: time_in_force.clone(),
+            price: order.order_price,
+            quantity: order.displayed_quantity(),
+        });
+
+        let market = self.state.market_mut(market_name)?;
+
+        // An auction market only queues the order, it trades at the next clearing
+        if config.matching == MatchingMode::BatchAuction{
+            if let Some(own_order_id) = market.crossed_own_order(&order)?{
+                return Err(OrderBookError::CrossesOwnOrder(own_order_id));
+            }
+            market.rest_order(order.clone())?;
+            self.state.order_index.insert(order.order_id, OrderLocation { market: market_name.to_string(), order_type: order.order_type.clone(), price: order.order_price, trigger: None });
+            return Ok(None);
+        }
+
+        let limit = match order_kind{
+            OrderKind::Limit => Some(order.order_price),
+            OrderKind::Market => None,
+        };
+
+        match time_in_force{
+            TimeInForce::PostOnly if market.crosses(&order.order_type, order.order_price, &clock)? => {
+                return Err(OrderBookError::PostOnlyWouldCross);
+            }
+            TimeInForce::Fok => {
+                let available = market.fillable_quantity(&order, limit, &clock)?;
+                if available < order.order_quantity{
+                    return Err(OrderBookError::FillOrKillUnfilled { required: order.order_quantity, available });
+                }
+            }
+            _ => {}
+        }
+
+        let mut order = order;
+        let mut purged = Purged::default();
+        let fills = match (&order_kind, &order.order_type){
+            _ if time_in_force == TimeInForce::PostOnly => Vec::new(),
+            (OrderKind::Market, OrderType::Bid) => match_market_bid(&mut order, market, &clock, &mut purged)?,
+            _ => match_order(&mut order, market, limit, &clock, &mut purged)?,
+        };
+
+        if order.order_quantity > 0{
+            let rests = order_kind == OrderKind::Limit && matches!(time_in_force, TimeInForce::Gtc | TimeInForce::PostOnly) && !purged.taker_cancelled;
+
+            if rests{
+                market.rest_order(order.clone())?;
+                self.state.order_index.insert(order.order_id, OrderLocation { market: market_name.to_string(), order_type: order.order_type.clone(), price: order.order_price, trigger: None });
+            }else{
+                // Whatever the order did not use goes back to its owner
+                let (refund_asset, refund_amount) = config.order_reservation(&order, &order_kind)?;
+                self.state.credit(&order.order_actor.0, &refund_asset, refund_amount);
+            }
+
+            if purged.taker_cancelled{
+                self.events.push(OrderBookEvent::SelfTradePrevented {
+                    order_id: order.order_id,
+                    actor: order.order_actor.0.clone(),
+                    market: market_name.to_string(),
+                    cancelled_quantity: order.order_quantity,
+                    remaining_quantity: 0,
+                });
+            }
+        }
+
+        self.release_purged(purged, market_name, &config)?;
+        self.settle_fills(fills, market_name, &config)
+
+    }
+
+    /// Places the trigger orders of the market that trades between the prices of `traded` reached,
+    /// taking in turn what each of them trades at
+    fn fire_triggers(&mut self, market_name: &str, mut traded: TradedRange) -> OrderBookResult<()>{
+
+        while let Some((low, high)) = traded{
+            let market = self.state.market_mut(market_name)?;
+            let Some(trigger_order) = market.pop_triggered(low, high)? else { break };
+            let config = market.config.clone();
+            let TriggerOrder { order, order_kind, trigger_price, .. } = trigger_order;
+
+            // Handing the reservation back, placing the order takes it again
+            let (reserved_asset, reserved_amount) = config.order_reservation(&order, &order_kind)?;
+            self.state.credit(&order.order_actor.0, &reserved_asset, reserved_amount);
+            self.state.unindex_order(order.order_id)?;
+
+            if order.is_expired(&self.state.clock){
+                self.events.push(OrderBookEvent::OrderExpired {
+                    order_id: order.order_id,
+                    actor: order.order_actor.0.clone(),
+                    market: market_name.to_string(),
+                    remaining_quantity: order.order_quantity,
+                });
+                continue;
+            }
+
+            self.events.push(OrderBookEvent::OrderTriggered {
+                order_id: order.order_id,
+                actor: order.order_actor.0.clone(),
+                market: market_name.to_string(),
+                trigger_price,
+            });
+
+            if let Some((order_low, order_high)) = self.place_order(order, order_kind, market_name)?{
+                traded = Some((low.min(order_low), high.max(order_high)));
+            }
+        }
+
+        Ok(())
+
+    }
+
+    pub fn amend_order(&mut self, order_id: u64, new_price: Price, new_quantity: u128) -> OrderBookResult<()>{
+
+        if new_price.0 == 0 {
+            return Err(OrderBookError::InvalidPrice);
+        }
+        if new_quantity == 0 {
+            return Err(OrderBookError::InvalidQuantity);
+        }
+
+        let Some(OrderLocation { market: market_name, order_type, price, trigger }) = self.state.order_location(order_id)?.cloned() else {
+            return Err(OrderBookError::OrderNotFound(order_id));
+        };
+        if trigger.is_some(){
+            return Err(OrderBookError::TriggerNotAmendable(order_id));
+        }
+
+        let clock = self.state.clock;
+        let market = self.state.market(&market_name)?;
+        if market.config.status != MarketStatus::Active{
+            return Err(OrderBookError::MarketNotActive(market_name.clone()));
+        }
+        market.config.check_quantity(new_quantity)?;
+        let config = market.config.clone();
+        let order = market.get_order(order_id, &order_type, price)?.ok_or(OrderBookError::OrderNotFound(order_id))?.clone();
+
+        if order.order_actor != self.identity{
+            return Err(OrderBookError::NotOrderOwner { order_id, identity: self.identity.0.clone() });
+        }
+        if order.is_expired(&clock){
+            return Err(OrderBookError::AlreadyExpired);
+        }
+
+        let mut amended = Order { order_price: new_price, order_quantity: new_quantity, ..order.clone() };
+        // Shrinking an order in place keeps its time priority, and an iceberg what is left of its peak
+        let in_place = new_price == order.order_price && new_quantity <= order.order_quantity;
+        if let Some(iceberg) = &mut amended.iceberg{
+            iceberg.displayed = if in_place { iceberg.displayed } else { iceberg.peak };
+        }
+
+        // Only reserving or refunding the difference with what the order already holds
+        let (reserved_asset, old_reserved) = config.reservation(&order)?;
+        let (_, new_reserved) = config.reservation(&amended)?;
+        if new_reserved > old_reserved{
+            self.state.debit(&self.identity.0, &reserved_asset, new_reserved - old_reserved)?;
+        }else{
+            self.state.credit(&self.identity.0, &reserved_asset, old_reserved - new_reserved);
+        }
+
+        self.events.push(OrderBookEvent::OrderAmended {
+            order_id,
+            actor: self.identity.0.clone(),
+            market: market_name.clone(),
+            price: new_price,
+            quantity: amended.displayed_quantity(),
+        });
+
+        let market = self.state.market_mut(&market_name)?;
+
+        if in_place{
+            *market.get_order_mut(order_id, &order_type, price)?.unwrap() = amended;
+            return Ok(());
+        }
+
+        // Anything else re-enters the book behind the orders already at its price, matching first if it now crosses
+        market.remove_order(order_id, &order_type, price)?;
+        let mut purged = Purged::default();
+        let fills = match config.matching{
+            // A post-only order stays a maker order, as when it was placed
+            MatchingMode::Continuous if amended.time_in_force == TimeInForce::PostOnly => {
+                if market.crosses(&order_type, new_price, &clock)?{
+                    return Err(OrderBookError::PostOnlyWouldCross);
+                }
+                Vec::new()
+            }
+            MatchingMode::Continuous => match_order(&mut amended, market, Some(new_price), &clock, &mut purged)?,
+            MatchingMode::BatchAuction => {
+                if let Some(own_order_id) = market.crossed_own_order(&amended)?{
+                    return Err(OrderBookError::CrossesOwnOrder(own_order_id));
+                }
+                Vec::new()
+            }
+        };
+
+        if amended.order_quantity > 0 && !purged.taker_cancelled{
+            market.rest_order(amended)?;
+            self.state.order_index.insert(order_id, OrderLocation { market: market_name.clone(), order_type, price: new_price, trigger: None });
+        }else{
+            self.state.unindex_order(order_id)?;
+
+            if amended.order_quantity > 0{
+                let (refund_asset, refund_amount) = config.reservation(&amended)?;
+                self.state.credit(&self.identity.0, &refund_asset, refund_amount);
+
+                self.events.push(OrderBookEvent::SelfTradePrevented {
+                    order_id,
+                    actor: self.identity.0.clone(),
+                    market: market_name.clone(),
+                    cancelled_quantity: amended.order_quantity,
+                    remaining_quantity: 0,
+                });
+            }
+        }
+
+        self.release_purged(purged, &market_name, &config)?;
+        let traded = self.settle_fills(fills, &market_name, &config)?;
+        self.fire_triggers(&market_name, traded)
+
+    }
+
+    pub fn clear_auction(&mut self, market_name: String) -> OrderBookResult<()>{
+
+        let clock = self.state.clock;
+        let market = self.state.market_mut(&market_name)?;
+        if market.config.status != MarketStatus::Active{
+            return Err(OrderBookError::MarketNotActive(market_name));
+        }
+        if market.config.matching != MatchingMode::BatchAuction{
+            return Err(OrderBookError::NotAuctionMarket(market_name));
+        }
+        let config = market.config.clone();
+
+        let auction = market.auction_price(&clock)?;
+        let mut purged = Purged::default();
+        let fills = match auction{
+            Some((price, volume)) => fill_auction(market, price, volume, &clock, &mut purged)?,
+            None => Vec::new(),
+        };
+
+        self.events.push(OrderBookEvent::AuctionCleared {
+            market: market_name.clone(),
+            price: auction.map(|(price, _)| price),
+            volume: auction.map_or(0, |(_, volume)| volume),
+        });
+
+        // Both orders of an auction fill were resting, so either may be used up
+        for fill in &fills{
+            for order_id in [fill.bid_order_id, fill.ask_order_id]{
+                if self.state.get_order(order_id)?.is_none(){
+                    self.state.unindex_order(order_id)?;
+                }
+            }
+        }
+
+        self.release_purged(purged, &market_name, &config)?;
+        self.settle_fills(fills, &market_name, &config)?;
+
+        Ok(())
+
+    }
+
+    /// Refunds what a matching pass took off the book without filling it, dropping the orders
+    /// that are gone from the order index
+    fn release_purged(&mut self, purged: Purged, market_name: &str, config: &MarketConfig) -> OrderBookResult<()>{
+
+        for order in purged.expired{
+            self.state.unindex_order(order.order_id)?;
+
+            let (refund_asset, refund_amount) = config.reservation(&order)?;
+            self.state.credit(&order.order_actor.0, &refund_asset, refund_amount);
+
+            self.events.push(OrderBookEvent::OrderExpired {
+                order_id: order.order_id,
+                actor: order.order_actor.0.clone(),
+                market: market_name.to_string(),
+                remaining_quantity: order.order_quantity,
+            });
+        }
+
+        for (order, cancelled_quantity) in purged.self_traded{
+            if order.order_quantity == 0{
+                self.state.unindex_order(order.order_id)?;
+            }
+
+            // Releasing the difference keeps a partly cancelled bid's reservation exact
+            let before = Order { order_quantity: order.order_quantity + cancelled_quantity, ..order.clone() };
+            let (refund_asset, reserved_before) = config.reservation(&before)?;
+            let (_, reserved_after) = config.reservation(&order)?;
+            self.state.credit(&order.order_actor.0, &refund_asset, reserved_before - reserved_after);
+
+            self.events.push(OrderBookEvent::SelfTradePrevented {
+                order_id: order.order_id,
+                actor: order.order_actor.0.clone(),
+                market: market_name.to_string(),
+                cancelled_quantity,
+                remaining_quantity: order.order_quantity,
+            });
+        }
+
+        Ok(())
+
+    }
+
+    /// Settles every fill of a matching pass and drops the makers it used up from the order index,
+    /// returning the lowest and highest price traded at
+    fn settle_fills(&mut self, fills: Vec<Fill>, market_name: &str, config: &MarketConfig) -> OrderBookResult<TradedRange>{
+
+        let Some(last_fill) = fills.last() else { return Ok(None) };
+        self.state.market_mut(market_name)?.last_trade_price = Some(last_fill.price);
+
+        let low = fills.iter().map(|fill| fill.price).min().unwrap();
+        let high = fills.iter().map(|fill| fill.price).max().unwrap();
+
+        for fill in fills{
+            let maker_id = match fill.taker_side{
+                OrderType::Bid => fill.ask_order_id,
+                OrderType::Ask => fill.bid_order_id,
+            };
+            if self.state.get_order(maker_id)?.is_none(){
+                self.state.unindex_order(maker_id)?;
+            }
+
+            let settled = settlement::settle_fill(&fill, config, &self.state.fee_collector)?;
+            for delta in settled.deltas{
+                self.state.credit(&delta.identity, &delta.asset, delta.amount);
+            }
+            self.events.push(fill.as_event(market_name, settled.maker_fee, settled.taker_fee));
+        }
+
+        Ok(Some((low, high)))
+
+    }
+
+    pub fn cancel_order(&mut self, order_id: u64) -> OrderBookResult<()>{
+
+        let Some(OrderLocation { market: market_name, order_type, price, trigger }) = self.state.order_location(order_id)?.cloned() else {
+            return Err(OrderBookError::OrderNotFound(order_id));
+        };
+
+        let market = self.state.market_mut(&market_name)?;
+
+        let order = match &trigger{
+            Some(condition) => market.get_trigger(order_id, condition, price)?.map(|t| &t.order),
+            None => market.get_order(order_id, &order_type, price)?,
+        };
+        match order{
+            Some(order) if order.order_actor != self.identity => {
+                return Err(OrderBookError::NotOrderOwner { order_id, identity: self.identity.0.clone() });
+            }
+            Some(_) => {}
+            None => return Err(OrderBookError::OrderNotFound(order_id)),
+        }
+
+        let (order, order_kind) = match &trigger{
+            Some(condition) => {
+                let trigger_order = market.remove_trigger(order_id, condition, price)?.unwrap();
+                (trigger_order.order, trigger_order.order_kind)
+            }
+            None => (market.remove_order(order_id, &order_type, price)?.unwrap(), OrderKind::Limit),
+        };
+
+        // Crediting back whatever was reserved for the unfilled part of the order
+        let (refund_asset, refund_amount) = market.config.order_reservation(&order, &order_kind)?;
+        self.state.unindex_order(order_id)?;
+
+        self.state.credit(&self.identity.0, &refund_asset, refund_amount);
+
+        self.events.push(OrderBookEvent::OrderCancelled {
+            order_id,
+            actor: self.identity.0.clone(),
+            market: market_name.clone(),
+            remaining_quantity: order.order_quantity,
+        });
+
+        Ok(())
+
+    }
+
+}
+
+/// Lowest and highest price a matching pass traded at, if it traded
+type TradedRange = Option<(Price, Price)>;
+
+/// Orders a matching pass took off the book, or cut down, without filling them
+#[derive(Default)]
+struct Purged{
+    /// Expired counter-orders met on the way
+    expired: Vec<Order>,
+    /// Orders cut down by self-trade prevention, each with the quantity it lost
+    self_traded: Vec<(Order, u128)>,
+    /// Whether self-trade prevention cancelled what is left of the incoming order
+    taker_cancelled: bool,
+}
+
+/// Applies `mode` to `order` and the best resting order of `side`, which belongs to the same
+/// identity, returning whether matching can go on
+fn prevent_self_trade(order: &mut Order, mode: SelfTradePrevention, market: &mut Market, side: &OrderType, purged: &mut Purged) -> OrderBookResult<bool> {
+    match mode {
+        SelfTradePrevention::CancelNewest => {
+            purged.taker_cancelled = true;
+            Ok(false)
+        }
+        SelfTradePrevention::CancelOldest | SelfTradePrevention::CancelBoth => {
+            let resting = market.pop_best(side).unwrap();
+            let cancelled_quantity = resting.order_quantity;
+            purged.self_traded.push((Order { order_quantity: 0, ..resting }, cancelled_quantity));
+
+            purged.taker_cancelled = mode == SelfTradePrevention::CancelBoth;
+            Ok(!purged.taker_cancelled)
+        }
+        SelfTradePrevention::DecrementAndCancel => {
+            let resting = market.best_mut(side)?.unwrap();
+            let cancelled_quantity = std::cmp::min(order.order_quantity, resting.order_quantity);
+
+            resting.reduce(cancelled_quantity);
+            purged.self_traded.push((resting.clone(), cancelled_quantity));
+            market.refresh_best(side)?;
+
+            order.order_quantity -= cancelled_quantity;
+            purged.self_traded.push((order.clone(), cancelled_quantity));
+            Ok(true)
+        }
+    }
+}
+
+/// Matches `order` against the opposite side of `market` in price-time priority until it is
+/// exhausted or the best counter-order no longer crosses `limit`. Without a limit any price is taken.
+/// Expired counter-orders and self-trades met on the way end up in `purged`.
+fn match_order(order: &mut Order, market: &mut Market, limit: Option<Price>, clock: &Clock, purged: &mut Purged) -> OrderBookResult<Vec<Fill>> {
+    let mut fills = Vec::new();
+
+    match order.order_type{
+        OrderType::Ask =>{
+
+            // The oldest order of the best bid level is always the next to match
+            while order.order_quantity > 0 {
+                let Some(matched_order) = market.best_mut(&OrderType::Bid)? else { break };
+                if matched_order.is_expired(clock) {
+                    purged.expired.push(market.pop_best(&OrderType::Bid).unwrap());
+                    continue;
+                }
+                if limit.is_some_and(|limit| matched_order.order_price < limit) {
+                    break;
+                }
+                if matched_order.order_actor == order.order_actor {
+                    let mode = order.self_trade_prevention;
+                    if !prevent_self_trade(order, mode, market, &OrderType::Bid, purged)? {
+                        break;
+                    }
+                    continue;
+                }
+
+                let matched_quantity = std::cmp::min(order.order_quantity, matched_order.displayed_quantity());
+                // Nothing left to take from the best order means the book would never move again
+                if matched_quantity == 0 {
+                    break;
+                }
+                fills.push(Fill {
+                    bid_order_id: matched_order.order_id,
+                    ask_order_id: order.order_id,
+                    bid_actor: matched_order.order_actor.clone(),
+                    ask_actor: order.order_actor.clone(),
+                    quantity: matched_quantity,
+                    price: matched_order.order_price,
+                    taker_side: OrderType::Ask,
+                    bid_reservation: BidReservation::Limit {
+                        price: matched_order.order_price,
+                        remaining: matched_order.order_quantity - matched_quantity,
+                    },
+                });
+
+                matched_order.reduce(matched_quantity);
+                market.refresh_best(&OrderType::Bid)?;
+                order.order_quantity -= matched_quantity;
+            }
+        }
+
+        OrderType::Bid => {
+
+            // The oldest order of the best ask level is always the next to match
+            while order.order_quantity > 0 {
+                let Some(matched_order) = market.best_mut(&OrderType::Ask)? else { break };
+                if matched_order.is_expired(clock) {
+                    purged.expired.push(market.pop_best(&OrderType::Ask).unwrap());
+                    continue;
+                }
+                if limit.is_some_and(|limit| matched_order.order_price > limit) {
+                    break;
+                }
+                if matched_order.order_actor == order.order_actor {
+                    let mode = order.self_trade_prevention;
+                    if !prevent_self_trade(order, mode, market, &OrderType::Ask, purged)? {
+                        break;
+                    }
+                    continue;
+                }
+
+                let matched_quantity = std::cmp::min(order.order_quantity, matched_order.displayed_quantity());
+                // Nothing left to take from the best order means the book would never move again
+                if matched_quantity == 0 {
+                    break;
+                }
+                fills.push(Fill {
+                    bid_order_id: order.order_id,
+                    ask_order_id: matched_order.order_id,
+                    bid_actor: order.order_actor.clone(),
+                    ask_actor: matched_order.order_actor.clone(),
+                    quantity: matched_quantity,
+                    price: matched_order.order_price,
+                    taker_side: OrderType::Bid,
+                    bid_reservation: match limit {
+                        Some(price) => BidReservation::Limit { price, remaining: order.order_quantity - matched_quantity },
+                        None => BidReservation::Budget,
+                    },
+                });
+
+                matched_order.reduce(matched_quantity);
+                market.refresh_best(&OrderType::Ask)?;
+                order.order_quantity -= matched_quantity;
+            }
+        }
+    }
+
+    Ok(fills)
+}
+
+/// Fills `volume` of the crossing orders of `market` at the single `price`, in price-time priority
+/// on both sides. Of the two orders of a fill, the one placed last pays the taker fee.
+/// Expired orders met on the way end up in `purged`.
+fn fill_auction(market: &mut Market, price: Price, mut volume: u128, clock: &Clock, purged: &mut Purged) -> OrderBookResult<Vec<Fill>> {
+    let mut fills = Vec::new();
+
+    while volume > 0 {
+        let Some(bid) = market.best_mut(&OrderType::Bid)?.cloned() else { break };
+        if bid.is_expired(clock) {
+            purged.expired.push(market.pop_best(&OrderType::Bid).unwrap());
+            continue;
+        }
+        let Some(ask) = market.best_mut(&OrderType::Ask)?.cloned() else { break };
+        if ask.is_expired(clock) {
+            purged.expired.push(market.pop_best(&OrderType::Ask).unwrap());
+            continue;
+        }
+        if bid.order_price < price || ask.order_price > price {
+            break;
+        }
+
+        let quantity = std::cmp::min(volume, std::cmp::min(bid.order_quantity, ask.order_quantity));
+        if quantity == 0 {
+            break;
+        }
+        fills.push(Fill {
+            bid_order_id: bid.order_id,
+            ask_order_id: ask.order_id,
+            bid_actor: bid.order_actor.clone(),
+            ask_actor: ask.order_actor.clone(),
+            quantity,
+            price,
+            taker_side: if bid.order_id > ask.order_id { OrderType::Bid } else { OrderType::Ask },
+            bid_reservation: BidReservation::Limit {
+                price: bid.order_price,
+                remaining: bid.order_quantity - quantity,
+            },
+        });
+
+        for order_type in [OrderType::Bid, OrderType::Ask] {
+            market.best_mut(&order_type)?.unwrap().reduce(quantity);
+            market.refresh_best(&order_type)?;
+        }
+        volume -= quantity;
+    }
+
+    Ok(fills)
+}
+
+/// Matches a market bid whose `order_quantity` is a quote-asset budget rather than a quantity,
+/// buying from the best asks for as long as the budget affords at least one unit and its taker fee.
+/// On return `order_quantity` holds the unspent budget.
+fn match_market_bid(order: &mut Order, market: &mut Market, clock: &Clock, purged: &mut Purged) -> OrderBookResult<Vec<Fill>> {
+    let mut fills = Vec::new();
+    let (tick_size, taker_bps, lot_size) = (market.config.tick_size, market.config.fees.taker_bps, market.config.lot_size);
+
+    while order.order_quantity > 0 {
+        let Some(matched_order) = market.best_mut(&OrderType::Ask)? else { break };
+        if matched_order.is_expired(clock) {
+            purged.expired.push(market.pop_best(&OrderType::Ask).unwrap());
+            continue;
+        }
+        if matched_order.order_actor == order.order_actor {
+            let mode = match order.self_trade_prevention {
+                SelfTradePrevention::DecrementAndCancel => SelfTradePrevention::CancelNewest,
+                mode => mode,
+            };
+            if !prevent_self_trade(order, mode, market, &OrderType::Ask, purged)? {
+                break;
+            }
+            continue;
+        }
+        let Some(unit_cost) = matched_order.order_price.notional(1, tick_size) else { break };
+        let cost = |quantity: u128| -> Option<u128> {
+            let notional = unit_cost.checked_mul(quantity)?;
+            let fee = u128::try_from(fees::fee_for(notional, taker_bps).ok()?).ok()?;
+            notional.checked_add(fee)
+        };
+
+        // Estimating from the unrounded fee rate, then taking the few units rounding the fee down may still afford
+        let Some(unit_with_fee) = unit_cost.checked_mul(10_000 + taker_bps.max(0) as u128) else { break };
+        let estimate = match order.order_quantity.checked_mul(10_000) {
+            Some(budget) => budget / unit_with_fee,
+            None => order.order_quantity / unit_with_fee * 10_000,
+        };
+        let displayed = matched_order.displayed_quantity();
+        let mut matched_quantity = std::cmp::min(estimate, displayed);
+        while matched_quantity < displayed && cost(matched_quantity + 1).is_some_and(|c| c <= order.order_quantity) {
+            matched_quantity += 1;
+        }
+        matched_quantity -= matched_quantity % lot_size;
+        let Some(matched_cost) = cost(matched_quantity).filter(|_| matched_quantity > 0) else { break };
+
+        fills.push(Fill {
+            bid_order_id: order.order_id,
+            ask_order_id: matched_order.order_id,
+            bid_actor: order.order_actor.clone(),
+            ask_actor: matched_order.order_actor.clone(),
+            quantity: matched_quantity,
+            price: matched_order.order_price,
+            taker_side: OrderType::Bid,
+            bid_reservation: BidReservation::Budget,
+        });
+
+        matched_order.reduce(matched_quantity);
+        market.refresh_best(&OrderType::Ask)?;
+        order.order_quantity -= matched_cost;
+    }
+
+    Ok(fills)
+}
+
+impl Digestable for OrderBookState{
+
+    /// Digest of the state commitment, see [`commitment::commit`]
+    fn as_digest(&self) -> sdk::StateDigest {
+        commitment::commit(self).as_digest()
+    }
+
+}
+
+impl Digestable for StateCommitment{
+
+    fn as_digest(&self) -> sdk::StateDigest {
+        sdk::StateDigest(
+            bincode::encode_to_vec(self, bincode::config::standard())
+                .expect("Failed to encode StateCommitment"),
+        )
+    }
+
+}
+
+impl From<sdk::StateDigest> for StateCommitment {
+    fn from(state: sdk::StateDigest) -> Self {
+        let (commitment, _) =
+            bincode::decode_from_slice(&state.0, bincode::config::standard())
+                .expect("Could not decode StateCommitment");
+        commitment
+    }
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn contract() -> OrderBookContract{
+        let mut state = OrderBookState::new("fees".to_string(), "admin".to_string());
+        state.credit("alice", "TOKA", 100);
+        state.credit("bob", "USDC", 5_000);
+        let config = MarketConfig::for_test("TOKA", "USDC");
+        state.markets.insert(config.market_name(), Market::new(config));
+
+        OrderBookContract::new(Identity("admin".to_string()), ContractName("orderbook".to_string()), state)
+    }
+
+    /// Bob's ask of 4 at 10 rests ahead of alice's ask of 6 at 11
+    fn contract_with_own_ask() -> OrderBookContract{
+        let mut contract = contract();
+        contract.state.credit("bob", "TOKA", 100);
+        contract.insert_order(Order::for_test(0, "bob", OrderType::Ask, 10, 4), OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+        contract.insert_order(Order::for_test(1, "alice", OrderType::Ask, 11, 6), OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+        contract
+    }
+
+    fn clock(block_height: u64, timestamp: u64) -> Clock{
+        Clock { block_height, timestamp }
+    }
+
+    #[test]
+    fn clock_never_goes_back(){
+        let mut contract = contract();
+        contract.advance_clock(clock(5, 100)).unwrap();
+
+        assert_eq!(contract.advance_clock(clock(4, 200)), Err(OrderBookError::ClockGoesBack));
+        assert_eq!(contract.advance_clock(clock(6, 50)), Err(OrderBookError::ClockGoesBack));
+        assert_eq!(contract.state.clock, clock(5, 100));
+
+        contract.advance_clock(clock(5, 100)).unwrap();
+    }
+
+    #[test]
+    fn order_expires_once_the_clock_reaches_its_expiry(){
+        let by_height = Order { order_expiry: Some(Expiry::BlockHeight(5)), ..Order::for_test(0, "alice", OrderType::Ask, 10, 10) };
+        let by_timestamp = Order { order_expiry: Some(Expiry::Timestamp(100)), ..by_height.clone() };
+
+        assert!(!by_height.is_expired(&clock(4, 1_000)));
+        assert!(by_height.is_expired(&clock(5, 0)));
+        assert!(!by_timestamp.is_expired(&clock(1_000, 99)));
+        assert!(by_timestamp.is_expired(&clock(0, 100)));
+        assert!(!Order::for_test(0, "alice", OrderType::Ask, 10, 10).is_expired(&clock(u64::MAX, u64::MAX)));
+    }
+
+    #[test]
+    fn expired_order_is_refused(){
+        let mut contract = contract();
+        contract.advance_clock(clock(5, 100)).unwrap();
+
+        let order = Order { order_expiry: Some(Expiry::BlockHeight(5)), ..Order::for_test(0, "alice", OrderType::Ask, 10, 10) };
+        assert_eq!(contract.insert_order(order, OrderKind::Limit, "TOKA/USDC".to_string()), Err(OrderBookError::AlreadyExpired));
+        assert_eq!(contract.state.balance("alice", "TOKA"), 100);
+    }
+
+    #[test]
+    fn expired_order_is_purged_and_refunded_instead_of_matched(){
+        let mut contract = contract();
+        let ask = Order { order_expiry: Some(Expiry::BlockHeight(5)), ..Order::for_test(0, "alice", OrderType::Ask, 10, 10) };
+        contract.insert_order(ask, OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+        assert_eq!(contract.state.balance("alice", "TOKA"), 90);
+
+        contract.advance_clock(clock(5, 100)).unwrap();
+        contract.events.clear();
+        contract.insert_order(Order::for_test(1, "bob", OrderType::Bid, 10, 10), OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+
+        assert!(contract.events.contains(&OrderBookEvent::OrderExpired { order_id: 0, actor: "alice".to_string(), market: "TOKA/USDC".to_string(), remaining_quantity: 10 }));
+        assert!(!contract.events.iter().any(|event| matches!(event, OrderBookEvent::OrderFilled { .. })));
+        assert_eq!(contract.state.balance("alice", "TOKA"), 100);
+        assert_eq!(contract.state.order_location(0).unwrap(), None);
+        assert!(contract.state.order_location(1).unwrap().is_some());
+    }
+    #[test]
+    fn fill_or_kill_counts_only_what_self_trade_prevention_lets_it_use(){
+        // Cancelling the newest or both stops at bob's own ask, cancelling the oldest skips it,
+        // and decrementing uses it up like a fill
+        let modes = [
+            (SelfTradePrevention::CancelNewest, 0),
+            (SelfTradePrevention::CancelBoth, 0),
+            (SelfTradePrevention::CancelOldest, 6),
+            (SelfTradePrevention::DecrementAndCancel, 10),
+        ];
+
+        for (mode, available) in modes{
+            let bid = |quantity| Order { time_in_force: TimeInForce::Fok, self_trade_prevention: mode, ..Order::for_test(2, "bob", OrderType::Bid, 11, quantity) };
+
+            let result = contract_with_own_ask().insert_order(bid(available + 1), OrderKind::Limit, "TOKA/USDC".to_string());
+            assert_eq!(result, Err(OrderBookError::FillOrKillUnfilled { required: available + 1, available }), "{mode:?}");
+
+            if available > 0{
+                let mut contract = contract_with_own_ask();
+                contract.insert_order(bid(available), OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+
+                let market = contract.state.market("TOKA/USDC").unwrap();
+                assert_eq!(market.orders(&OrderType::Ask).count(), 0, "{mode:?}");
+                assert_eq!(market.orders(&OrderType::Bid).count(), 0, "{mode:?}");
+            }
+        }
+    }
+    #[test]
+    fn post_only_amend_that_would_cross_is_refused(){
+        // Bob's bid at 9 rests below alice's post-only ask at 11
+        let book = || {
+            let mut contract = contract();
+            contract.insert_order(Order::for_test(0, "bob", OrderType::Bid, 9, 4), OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+            let ask = Order { time_in_force: TimeInForce::PostOnly, ..Order::for_test(1, "alice", OrderType::Ask, 11, 6) };
+            contract.insert_order(ask, OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+            contract.identity = Identity("alice".to_string());
+            contract
+        };
+
+        assert_eq!(book().amend_order(1, Price(9), 6), Err(OrderBookError::PostOnlyWouldCross));
+
+        let mut contract = book();
+        contract.amend_order(1, Price(10), 6).unwrap();
+        let market = contract.state.market("TOKA/USDC").unwrap();
+        assert_eq!(market.get_order(1, &OrderType::Ask, Price(10)).unwrap().map(|order| order.order_quantity), Some(6));
+        assert_eq!(market.get_order(0, &OrderType::Bid, Price(9)).unwrap().map(|order| order.order_quantity), Some(4));
+    }
+    #[test]
+    fn iceberg_needs_a_peak(){
+        let mut contract = contract();
+        let ask = Order { iceberg: Some(Iceberg::new(0)), ..Order::for_test(0, "alice", OrderType::Ask, 10, 10) };
+
+        assert_eq!(contract.insert_order(ask, OrderKind::Limit, "TOKA/USDC".to_string()), Err(OrderBookError::InvalidIceberg));
+    }
+
+    #[test]
+    fn iceberg_shows_a_new_peak_once_its_peak_is_used_up(){
+        let mut contract = contract();
+        let ask = Order { iceberg: Some(Iceberg::new(4)), ..Order::for_test(0, "alice", OrderType::Ask, 10, 10) };
+        contract.insert_order(ask, OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+        contract.insert_order(Order::for_test(1, "bob", OrderType::Bid, 10, 4), OrderKind::Limit, "TOKA/USDC".to_string()).unwrap();
+
+        let ask = contract.state.market("TOKA/USDC").unwrap().get_order(0, &OrderType::Ask, Price(10)).unwrap().unwrap();
+        assert_eq!(ask.order_quantity, 6);
+        assert_eq!(ask.displayed_quantity(), 4);
+    }
+    #[test]
+    fn anyone_may_clear_an_auction(){
+        let mut contract = contract();
+        contract.identity = Identity("alice".to_string());
+
+        assert_eq!(contract.authorize(&OrderBookAction::ClearAuction { market: "TOKA/USDC".to_string() }), Ok(()));
+        assert!(contract.authorize(&OrderBookAction::DelistMarket { market: "TOKA/USDC".to_string() }).is_err());
+    }
+}
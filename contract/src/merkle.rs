@@ -0,0 +1,242 @@
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// Hash of an empty subtree, whatever its depth
+pub const EMPTY: Hash = [0; 32];
+
+/// Keys are 256-bit paths from the root, so every leaf sits at this depth
+const DEPTH: usize = 256;
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Sibling hashes needed to recompute the root from a set of leaves, in the order
+/// [`roots`] consumes them. `None` stands for an empty subtree.
+pub type MerkleProof = Vec<Option<Hash>>;
+
+/// Path of a leaf in the tree
+pub fn key_hash(key: &[u8]) -> Hash {
+    Sha256::digest(key).into()
+}
+
+pub fn leaf_hash(key: &Hash, value: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+/// Two empty children make an empty node, so untouched parts of the tree cost nothing
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    if *left == EMPTY && *right == EMPTY {
+        return EMPTY;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn bit(key: &Hash, depth: usize) -> bool {
+    key[depth / 8] >> (7 - depth % 8) & 1 == 1
+}
+
+/// Index of the first entry going right at `depth`, entries being sorted by key
+fn split<T>(entries: &[T], depth: usize, key: impl Fn(&T) -> &Hash) -> usize {
+    entries.partition_point(|entry| !bit(key(entry), depth))
+}
+
+/// Root of the tree holding exactly `leaves`, given as `(key, leaf hash)` sorted by key
+pub fn root(leaves: &[(Hash, Hash)]) -> Hash {
+    subtree(0, leaves)
+}
+
+fn subtree(depth: usize, leaves: &[(Hash, Hash)]) -> Hash {
+    match leaves {
+        [] => EMPTY,
+        [(_, leaf)] if depth == DEPTH => *leaf,
+        _ => {
+            let middle = split(leaves, depth, |(key, _)| key);
+            node_hash(
+                &subtree(depth + 1, &leaves[..middle]),
+                &subtree(depth + 1, &leaves[middle..]),
+            )
+        }
+    }
+}
+
+/// Proves the leaves at `keys` within the tree holding `leaves`, both sorted by key
+pub fn prove(leaves: &[(Hash, Hash)], keys: &[Hash]) -> MerkleProof {
+    let mut proof = Vec::new();
+    if !keys.is_empty() {
+        prove_subtree(0, leaves, keys, &mut proof);
+    }
+    proof
+}
+
+fn prove_subtree(depth: usize, leaves: &[(Hash, Hash)], keys: &[Hash], proof: &mut MerkleProof) {
+    if depth == DEPTH {
+        return;
+    }
+
+    let leaves_middle = split(leaves, depth, |(key, _)| key);
+    let keys_middle = split(keys, depth, |key| key);
+
+    for (leaves, keys) in [
+        (&leaves[..leaves_middle], &keys[..keys_middle]),
+        (&leaves[leaves_middle..], &keys[keys_middle..]),
+    ] {
+        if keys.is_empty() {
+            let sibling = subtree(depth + 1, leaves);
+            proof.push((sibling != EMPTY).then_some(sibling));
+        } else {
+            prove_subtree(depth + 1, leaves, keys, proof);
+        }
+    }
+}
+
+/// Recomputes the root before and after replacing leaves, given as
+/// `(key, old leaf hash, new leaf hash)` sorted by key, an absent leaf hashing to [`EMPTY`].
+///
+/// Returns `None` when `proof` does not have the shape `entries` call for.
+pub fn roots(entries: &[(Hash, Hash, Hash)], proof: &[Option<Hash>]) -> Option<(Hash, Hash)> {
+    if entries.is_empty() {
+        return None;
+    }
+
+    let mut siblings = proof.iter();
+    let roots = roots_subtree(0, entries, &mut siblings)?;
+
+    // Every sibling must be used, so a proof has exactly one reading
+    match siblings.next() {
+        Some(_) => None,
+        None => Some(roots),
+    }
+}
+
+fn roots_subtree<'a>(
+    depth: usize,
+    entries: &[(Hash, Hash, Hash)],
+    siblings: &mut impl Iterator<Item = &'a Option<Hash>>,
+) -> Option<(Hash, Hash)> {
+    if depth == DEPTH {
+        return match entries {
+            [(_, old, new)] => Some((*old, *new)),
+            _ => None,
+        };
+    }
+
+    let middle = split(entries, depth, |(key, _, _)| key);
+    let mut children = [(EMPTY, EMPTY); 2];
+
+    for (child, entries) in children.iter_mut().zip([&entries[..middle], &entries[middle..]]) {
+        *child = if entries.is_empty() {
+            let sibling = siblings.next()?.unwrap_or(EMPTY);
+            (sibling, sibling)
+        } else {
+            roots_subtree(depth + 1, entries, siblings)?
+        };
+    }
+
+    let [(old_left, new_left), (old_right, new_right)] = children;
+    Some((node_hash(&old_left, &old_right), node_hash(&new_left, &new_right)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(name: &str, value: &str) -> (Hash, Hash) {
+        let key = key_hash(name.as_bytes());
+        (key, leaf_hash(&key, value.as_bytes()))
+    }
+
+    fn sorted(mut leaves: Vec<(Hash, Hash)>) -> Vec<(Hash, Hash)> {
+        leaves.sort();
+        leaves
+    }
+
+    fn tree() -> Vec<(Hash, Hash)> {
+        sorted(vec![
+            leaf("a", "1"),
+            leaf("b", "2"),
+            leaf("c", "3"),
+            leaf("d", "4"),
+        ])
+    }
+
+    #[test]
+    fn empty_tree_has_empty_root() {
+        assert_eq!(root(&[]), EMPTY);
+    }
+
+    #[test]
+    fn roots_round_trip_update_insert_and_delete() {
+        let leaves = tree();
+        let (updated_key, updated) = leaf("b", "20");
+        let (inserted_key, inserted) = leaf("e", "5");
+        let (deleted_key, deleted) = leaf("c", "3");
+
+        let mut entries = vec![
+            (updated_key, leaf("b", "2").1, updated),
+            (inserted_key, EMPTY, inserted),
+            (deleted_key, deleted, EMPTY),
+        ];
+        entries.sort();
+        let mut keys: Vec<Hash> = entries.iter().map(|(key, _, _)| *key).collect();
+        keys.sort();
+
+        let proof = prove(&leaves, &keys);
+        let after = sorted(vec![
+            leaf("a", "1"),
+            (updated_key, updated),
+            leaf("d", "4"),
+            (inserted_key, inserted),
+        ]);
+
+        assert_eq!(roots(&entries, &proof), Some((root(&leaves), root(&after))));
+    }
+
+    #[test]
+    fn roots_rejects_tampered_values_and_siblings() {
+        let leaves = tree();
+        let (key, hash) = leaf("a", "1");
+        let proof = prove(&leaves, &[key]);
+        let expected = root(&leaves);
+
+        let (_, tampered) = leaf("a", "10");
+        let (old, _) = roots(&[(key, tampered, tampered)], &proof).unwrap();
+        assert_ne!(old, expected);
+
+        let mut tampered_proof = proof.clone();
+        let sibling = tampered_proof.iter_mut().find_map(Option::as_mut).unwrap();
+        sibling[0] ^= 1;
+        let (old, _) = roots(&[(key, hash, hash)], &tampered_proof).unwrap();
+        assert_ne!(old, expected);
+    }
+
+    #[test]
+    fn roots_rejects_proofs_of_the_wrong_shape() {
+        let leaves = tree();
+        let (key, hash) = leaf("a", "1");
+        let proof = prove(&leaves, &[key]);
+
+        let mut extra = proof.clone();
+        extra.push(None);
+        assert_eq!(roots(&[(key, hash, hash)], &extra), None);
+        assert_eq!(roots(&[(key, hash, hash)], &proof[1..]), None);
+        assert_eq!(roots(&[], &[]), None);
+    }
+
+    #[test]
+    fn roots_rejects_duplicated_entries() {
+        let leaves = tree();
+        let (key, hash) = leaf("a", "1");
+        let proof = prove(&leaves, &[key, key]);
+
+        assert_eq!(roots(&[(key, hash, hash), (key, hash, hash)], &proof), None);
+    }
+}
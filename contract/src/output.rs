@@ -0,0 +1,15 @@
+use bincode::{Decode, Encode};
+
+/// Program outputs are carried as strings, so values are bincode-encoded then hex-encoded
+pub(crate) fn encode_output<T: Encode + ?Sized>(value: &T) -> String {
+    hex::encode(
+        bincode::encode_to_vec(value, bincode::config::standard())
+            .expect("Failed to encode program output"),
+    )
+}
+
+pub(crate) fn decode_output<T: Decode>(program_outputs: &[u8]) -> Option<T> {
+    let bytes = hex::decode(program_outputs).ok()?;
+    let (value, _) = bincode::decode_from_slice(&bytes, bincode::config::standard()).ok()?;
+    Some(value)
+}
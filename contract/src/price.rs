@@ -0,0 +1,38 @@
+use std::{fmt, num::ParseIntError, str::FromStr};
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+/// Limit price of an order, expressed as a whole number of ticks.
+///
+/// The quote-asset value of one tick is the `tick_size` of the market the order is placed in,
+/// so every amount derived from a price is an exact integer and never goes through floating point.
+#[derive(
+    Encode, Decode, Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Price(pub u64);
+
+impl Price {
+    /// Quote-asset amount for `quantity` units at this price, or `None` on overflow.
+    ///
+    /// Reservation and settlement both go through this, so they always agree on the amount.
+    pub fn notional(&self, quantity: u128, tick_size: u128) -> Option<u128> {
+        (self.0 as u128)
+            .checked_mul(tick_size)?
+            .checked_mul(quantity)
+    }
+}
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Price {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Price)
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{fees, Fill, MarketConfig, OrderBookError, OrderBookResult, OrderType, Price};
+
+/// Amount of `asset` credited to `identity` when a fill settles.
+///
+/// Both sides already had their side of the trade debited when their order was reserved,
+/// so settling only ever credits balances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceDelta {
+    pub identity: String,
+    pub asset: String,
+    pub amount: u128,
+}
+
+/// What the bid side of a fill had set aside to pay for it
+#[derive(Debug, Clone, PartialEq)]
+pub enum BidReservation {
+    /// A limit bid holds [`bid_reservation`] of its price and unfilled quantity, `remaining`
+    /// being that quantity once the fill is done
+    Limit { price: Price, remaining: u128 },
+    /// A market bid already paid for the fill out of its budget while matching
+    Budget,
+}
+
+/// Everything settling a fill produced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settlement {
+    pub deltas: Vec<BalanceDelta>,
+    pub maker_fee: i128,
+    pub taker_fee: i128,
+}
+
+/// Quote asset a limit bid holds for `quantity` units at `price`, fee headroom included
+pub fn bid_reservation(price: Price, quantity: u128, tick_size: u128) -> OrderBookResult<u128> {
+    let notional = price
+        .notional(quantity, tick_size)
+        .ok_or(OrderBookError::Overflow)?;
+    fees::with_fee_headroom(notional)
+}
+
+/// Turns a fill into the balance credits it owes.
+///
+/// The buyer receives the base token and pays the quote-token notional at the fill price plus
+/// its fee. The seller receives the notional minus its fee, and the fee collector what both
+/// fees add up to.
+/// A limit bid releases the part of its reservation that covered the filled quantity, so the
+/// price improvement and unused fee headroom on that quantity are refunded to the buyer.
+pub fn settle_fill(
+    fill: &Fill,
+    config: &MarketConfig,
+    fee_collector: &str,
+) -> OrderBookResult<Settlement> {
+    let (tick_size, fees) = (config.tick_size, &config.fees);
+    let notional = fill
+        .price
+        .notional(fill.quantity, tick_size)
+        .ok_or(OrderBookError::Overflow)?;
+
+    let maker_fee = fees::fee_for(notional, fees.maker_bps)?;
+    let taker_fee = fees::fee_for(notional, fees.taker_bps)?;
+    let (buyer_fee, seller_fee) = match fill.taker_side {
+        OrderType::Bid => (taker_fee, maker_fee),
+        OrderType::Ask => (maker_fee, taker_fee),
+    };
+
+    let notional = i128::try_from(notional).map_err(|_| OrderBookError::Overflow)?;
+    let buyer_cost = (notional + buyer_fee) as u128;
+    let seller_proceeds = (notional - seller_fee) as u128;
+    let collected = buyer_fee + seller_fee;
+
+    if collected < 0 {
+        return Err(OrderBookError::InvalidFeeSchedule);
+    }
+
+    let mut deltas = vec![
+        BalanceDelta {
+            identity: fill.bid_actor.0.clone(),
+            asset: config.base_token.clone(),
+            amount: fill.quantity,
+        },
+        BalanceDelta {
+            identity: fill.ask_actor.0.clone(),
+            asset: config.quote_token.clone(),
+            amount: seller_proceeds,
+        },
+    ];
+
+    if let BidReservation::Limit { price, remaining } = fill.bid_reservation {
+        let released = bid_reservation(price, remaining + fill.quantity, tick_size)?
+            - bid_reservation(price, remaining, tick_size)?;
+
+        if released < buyer_cost {
+            return Err(OrderBookError::ReservationShortfall {
+                order_id: fill.bid_order_id,
+            });
+        }
+
+        if released > buyer_cost {
+            deltas.push(BalanceDelta {
+                identity: fill.bid_actor.0.clone(),
+                asset: config.quote_token.clone(),
+                amount: released - buyer_cost,
+            });
+        }
+    }
+
+    if collected > 0 {
+        deltas.push(BalanceDelta {
+            identity: fee_collector.to_string(),
+            asset: config.quote_token.clone(),
+            amount: collected as u128,
+        });
+    }
+
+    Ok(Settlement {
+        deltas,
+        maker_fee,
+        taker_fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use sdk::Identity;
+
+    use super::*;
+    use crate::FeeSchedule;
+
+    fn config(fees: FeeSchedule) -> MarketConfig {
+        MarketConfig {
+            fees,
+            ..MarketConfig::for_test("TOKA", "USDC")
+        }
+    }
+
+    fn fill(
+        price: u64,
+        quantity: u128,
+        taker_side: OrderType,
+        bid_reservation: BidReservation,
+    ) -> Fill {
+        Fill {
+            bid_order_id: 1,
+            ask_order_id: 2,
+            bid_actor: Identity("bob".to_string()),
+            ask_actor: Identity("alice".to_string()),
+            quantity,
+            price: Price(price),
+            taker_side,
+            bid_reservation,
+        }
+    }
+
+    fn delta(identity: &str, asset: &str, amount: u128) -> BalanceDelta {
+        BalanceDelta {
+            identity: identity.to_string(),
+            asset: asset.to_string(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn buyer_gets_the_base_token_and_seller_the_notional() {
+        let fill = fill(12, 10, OrderType::Bid, BidReservation::Budget);
+        let settlement = settle_fill(&fill, &config(FeeSchedule::default()), "fees").unwrap();
+
+        assert_eq!(
+            settlement.deltas,
+            vec![delta("bob", "TOKA", 10), delta("alice", "USDC", 120)]
+        );
+    }
+
+    #[test]
+    fn limit_bid_is_refunded_its_price_improvement_and_headroom() {
+        // 15 * 10 reserved with 1 of fee headroom, of which the fill at 12 uses 120
+        let fill = fill(
+            12,
+            10,
+            OrderType::Ask,
+            BidReservation::Limit {
+                price: Price(15),
+                remaining: 0,
+            },
+        );
+        let settlement = settle_fill(&fill, &config(FeeSchedule::default()), "fees").unwrap();
+
+        assert_eq!(
+            settlement.deltas,
+            vec![
+                delta("bob", "TOKA", 10),
+                delta("alice", "USDC", 120),
+                delta("bob", "USDC", 31),
+            ]
+        );
+    }
+
+    #[test]
+    fn fill_above_the_reserved_price_is_refused() {
+        let fill = fill(
+            12,
+            10,
+            OrderType::Ask,
+            BidReservation::Limit {
+                price: Price(10),
+                remaining: 5,
+            },
+        );
+
+        assert_eq!(
+            settle_fill(&fill, &config(FeeSchedule::default()), "fees"),
+            Err(OrderBookError::ReservationShortfall { order_id: 1 })
+        );
+    }
+
+    #[test]
+    fn taker_pays_its_fee_and_maker_gets_its_rebate() {
+        let fees = FeeSchedule {
+            maker_bps: -10,
+            taker_bps: 30,
+        };
+        let fill = fill(100, 100, OrderType::Bid, BidReservation::Budget);
+        let settlement = settle_fill(&fill, &config(fees), "fees").unwrap();
+
+        // The buyer paid 10_030 out of its budget, the seller gets the notional plus its rebate
+        assert_eq!(
+            settlement,
+            Settlement {
+                deltas: vec![
+                    delta("bob", "TOKA", 100),
+                    delta("alice", "USDC", 10_010),
+                    delta("fees", "USDC", 20),
+                ],
+                maker_fee: -10,
+                taker_fee: 30,
+            }
+        );
+    }
+
+    #[test]
+    fn maker_bid_rebate_is_refunded_with_its_headroom() {
+        let fees = FeeSchedule {
+            maker_bps: -10,
+            taker_bps: 30,
+        };
+        let fill = fill(
+            100,
+            100,
+            OrderType::Ask,
+            BidReservation::Limit {
+                price: Price(100),
+                remaining: 0,
+            },
+        );
+        let settlement = settle_fill(&fill, &config(fees), "fees").unwrap();
+
+        // 10_100 reserved, of which the buyer's cost net of its rebate uses 9_990
+        assert_eq!(
+            settlement.deltas,
+            vec![
+                delta("bob", "TOKA", 100),
+                delta("alice", "USDC", 9_970),
+                delta("bob", "USDC", 110),
+                delta("fees", "USDC", 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn fees_round_towards_zero() {
+        let fees = FeeSchedule {
+            maker_bps: -10,
+            taker_bps: 30,
+        };
+        // 99 of notional is too little for either fee
+        let fill = fill(99, 1, OrderType::Bid, BidReservation::Budget);
+        let settlement = settle_fill(&fill, &config(fees), "fees").unwrap();
+
+        assert_eq!((settlement.maker_fee, settlement.taker_fee), (0, 0));
+        assert_eq!(
+            settlement.deltas,
+            vec![delta("bob", "TOKA", 1), delta("alice", "USDC", 99)]
+        );
+    }
+
+    #[test]
+    fn rebate_above_the_taker_fee_is_refused() {
+        let fees = FeeSchedule {
+            maker_bps: -30,
+            taker_bps: 10,
+        };
+        let fill = fill(100, 100, OrderType::Bid, BidReservation::Budget);
+
+        assert_eq!(
+            settle_fill(&fill, &config(fees), "fees"),
+            Err(OrderBookError::InvalidFeeSchedule)
+        );
+    }
+}
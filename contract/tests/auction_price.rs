@@ -0,0 +1,92 @@
+use contract_orderbook_app::{Clock, Market, MarketConfig, MatchingMode, Order, OrderType, Price};
+
+/// A batch auction market holding `orders`, given as `(order type, price, quantity)`
+fn market(orders: &[(OrderType, u64, u128)]) -> Market {
+    let mut market = Market::new(MarketConfig {
+        matching: MatchingMode::BatchAuction,
+        ..MarketConfig::for_test("TOKA", "USDC")
+    });
+
+    for (order_id, (order_type, price, quantity)) in orders.iter().enumerate() {
+        let actor = match order_type {
+            OrderType::Bid => "bob",
+            OrderType::Ask => "alice",
+        };
+        market
+            .rest_order(Order::for_test(
+                order_id as u64,
+                actor,
+                order_type.clone(),
+                *price,
+                *quantity,
+            ))
+            .unwrap();
+    }
+
+    market
+}
+
+#[test]
+fn uncrossed_book_has_no_auction_price() {
+    assert_eq!(market(&[]).auction_price(&Clock::default()).unwrap(), None);
+    assert_eq!(
+        market(&[(OrderType::Bid, 9, 5), (OrderType::Ask, 10, 5)])
+            .auction_price(&Clock::default())
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn auction_price_trades_the_most() {
+    // 4 trade at 9, 10 at 11, and the ask at 12 does not cross
+    let market = market(&[
+        (OrderType::Bid, 11, 10),
+        (OrderType::Ask, 9, 4),
+        (OrderType::Ask, 11, 6),
+        (OrderType::Ask, 12, 5),
+    ]);
+
+    assert_eq!(
+        market.auction_price(&Clock::default()).unwrap(),
+        Some((Price(11), 10))
+    );
+}
+
+#[test]
+fn auction_price_leaves_the_least_imbalance_among_equal_volumes() {
+    // 5 trade at both 8 and 10, leaving 3 of demand unfilled at 8 and none at 10
+    let market = market(&[
+        (OrderType::Bid, 10, 5),
+        (OrderType::Bid, 8, 3),
+        (OrderType::Ask, 8, 5),
+    ]);
+
+    assert_eq!(
+        market.auction_price(&Clock::default()).unwrap(),
+        Some((Price(10), 5))
+    );
+}
+
+#[test]
+fn auction_price_takes_the_lower_middle_of_tied_prices() {
+    assert_eq!(
+        market(&[(OrderType::Bid, 12, 5), (OrderType::Ask, 8, 5)])
+            .auction_price(&Clock::default())
+            .unwrap(),
+        Some((Price(8), 5))
+    );
+
+    // 5 trade at 8, 10 and 12, leaving 2 unfilled at each
+    assert_eq!(
+        market(&[
+            (OrderType::Bid, 12, 5),
+            (OrderType::Bid, 10, 2),
+            (OrderType::Ask, 8, 5),
+            (OrderType::Ask, 12, 2),
+        ])
+        .auction_price(&Clock::default())
+        .unwrap(),
+        Some((Price(10), 5))
+    );
+}
@@ -0,0 +1,80 @@
+use contract_orderbook_app::{
+    Clock, Market, MarketConfig, Order, OrderBookState, OrderLocation, OrderType,
+};
+use sdk::Digestable;
+
+/// Builds the same logical state, crediting balances, listing markets and resting orders in the given order
+fn build<'a>(
+    credits: impl Iterator<Item = &'a (&'a str, &'a str, u128)>,
+    markets: impl Iterator<Item = &'a (&'a str, &'a str)>,
+    orders: impl Iterator<Item = &'a (&'a str, Order)>,
+) -> OrderBookState {
+    let mut state = OrderBookState::new("fees".to_string(), "admin".to_string());
+    state.order_nonce = 4;
+    state.clock = Clock {
+        block_height: 10,
+        timestamp: 1_700_000_000,
+    };
+
+    for (identity, asset, amount) in credits {
+        state.credit(identity, asset, *amount);
+    }
+    for (base_token, quote_token) in markets {
+        let config = MarketConfig::for_test(base_token, quote_token);
+        state.markets.insert(config.market_name(), Market::new(config));
+    }
+    for (market, order) in orders {
+        state.order_index.insert(
+            order.order_id,
+            OrderLocation {
+                market: market.to_string(),
+                order_type: order.order_type.clone(),
+                price: order.order_price,
+                trigger: None,
+            },
+        );
+        state.markets.get_mut(*market).unwrap().rest_order(order.clone()).unwrap();
+    }
+
+    state
+}
+
+#[test]
+fn digest_does_not_depend_on_insertion_order() {
+    let credits = [
+        ("alice", "TOKA", 100),
+        ("bob", "USDC", 5_000),
+        ("alice", "USDC", 250),
+        ("carol", "HYLLAR", 7),
+        ("bob", "TOKA", 3),
+    ];
+    let markets = [("TOKA", "USDC"), ("TOKA", "HYLLAR"), ("HYLLAR", "USDC")];
+    // One order per price level, as the order within a level is its time priority
+    let orders = [
+        ("TOKA/USDC", Order::for_test(0, "alice", OrderType::Ask, 12, 10)),
+        ("TOKA/USDC", Order::for_test(1, "bob", OrderType::Bid, 9, 4)),
+        ("TOKA/HYLLAR", Order::for_test(2, "alice", OrderType::Ask, 30, 1)),
+        ("TOKA/USDC", Order::for_test(3, "bob", OrderType::Bid, 10, 2)),
+    ];
+
+    let forward = build(credits.iter(), markets.iter(), orders.iter());
+    let backward = build(credits.iter().rev(), markets.iter().rev(), orders.iter().rev());
+
+    assert_eq!(forward.as_digest(), backward.as_digest());
+    assert_eq!(
+        bincode::encode_to_vec(&forward, bincode::config::standard()).unwrap(),
+        bincode::encode_to_vec(&backward, bincode::config::standard()).unwrap(),
+    );
+}
+
+#[test]
+fn digest_ignores_emptied_balances() {
+    let credits = [("alice", "TOKA", 100)];
+    let untouched = build(credits.iter(), [].iter(), [].iter());
+
+    let mut emptied = build(credits.iter(), [].iter(), [].iter());
+    emptied.credit("bob", "USDC", 40);
+    emptied.debit("bob", "USDC", 40).unwrap();
+
+    assert_eq!(untouched.as_digest(), emptied.as_digest());
+}
@@ -1,7 +1,10 @@
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use clap::{Subcommand, Parser};
-use client_sdk::helpers::risc0::Risc0Prover;
+use client_sdk::{helpers::risc0::Risc0Prover, rest_client::NodeApiHttpClient};
 use contract_identity::IdentityContractState;
-use contract_orderbook_app::{OrderBookAction, OrderBookState, OrderType};
+use contract_orderbook_app::{commitment::{self, StateCommitment}, events::ActionOutput, Clock, Expiry, FeeSchedule, MarketConfig, MarketStatus, MatchingMode, OrderBookAction, OrderBookError, OrderBookState, OrderKind, OrderType, Price, PrivateInput, PrivateOrder, SelfTradePrevention, SubAction, TimeInForce, TriggerKind};
 use methods::{ZK_ORDERBOOK_ELF, ZK_ORDERBOOK_ID};
 use sdk::{identity_provider::IdentityAction, BlobTransaction, ContractInput, ContractName, Digestable, Identity, ProofTransaction, RegisterContractTransaction};
 use contract_token::TokenContractState;
@@ -29,14 +32,123 @@ pub struct Cli{
     #[arg(long, default_value = "0")]
     pub nonce: String,
 
+    /// Full orderbook state, the contract only keeping a commitment to it
+    #[arg(long, default_value = "orderbook_state.bin")]
+    pub state_file: String,
+
 }
 
 
 #[derive(Subcommand)]
 enum Commands {
-    Register { token: String },
+    Register {
+        /// Identity the trading fees are credited to, defaults to the user
+        #[arg(long)]
+        fee_collector: Option<String>,
+        /// Identity allowed to list and govern markets, defaults to the user
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    /// Lists the market trading `base_token` against `quote_token`
+    CreateMarket {
+        base_token: String,
+        quote_token: String,
+        /// Quote-asset value of one price tick
+        #[arg(long, default_value = "1")]
+        tick_size: u128,
+        /// Order quantities must be whole multiples of it
+        #[arg(long, default_value = "1")]
+        lot_size: u128,
+        #[arg(long, default_value = "1")]
+        min_order_size: u128,
+        /// Maker fee in basis points of the fill notional, negative for a rebate
+        #[arg(long, default_value = "0", allow_hyphen_values = true)]
+        maker_fee_bps: i32,
+        /// Taker fee in basis points of the fill notional
+        #[arg(long, default_value = "0")]
+        taker_fee_bps: i32,
+        /// Match orders in batch auctions anyone can clear instead of continuously
+        #[arg(long)]
+        auction: bool,
+    },
     DepositAsset { token:String, amount: u128 },
-    InsertOrder { token: String, price: f64, amount: u128, side: String },
+    /// Price is given in ticks of the market
+    InsertOrder {
+        /// Listed market, e.g. TOKA/USDC
+        pair: String,
+        price: Price,
+        amount: u128,
+        side: String,
+        /// Take liquidity right away instead of resting; the price is ignored and a buy's amount is the quote-asset budget
+        #[arg(long)]
+        market: bool,
+        /// One of gtc, ioc, fok or post-only
+        #[arg(long, default_value = "gtc")]
+        tif: String,
+        /// Block height at which a resting order expires, by the clock the admin advances
+        #[arg(long, conflicts_with = "expiry_timestamp")]
+        expiry_height: Option<u64>,
+        /// Unix timestamp in seconds at which a resting order expires, by the clock the admin advances
+        #[arg(long)]
+        expiry_timestamp: Option<u64>,
+        /// Self-trade prevention, one of cancel-newest, cancel-oldest, cancel-both or decrement-and-cancel
+        #[arg(long, default_value = "cancel-newest")]
+        stp: String,
+        /// Only publish a commitment to the order, revealing it to the prover alone
+        #[arg(long, conflicts_with_all = ["stop_loss", "take_profit"])]
+        private: bool,
+        /// Make a resting limit order an iceberg, showing at most this much of its amount at a time in events.
+        /// The full amount is still published in the state changes of the proof
+        #[arg(long, conflicts_with = "market")]
+        peak: Option<u128>,
+        /// Hold the order until a trade at or beyond this price goes against it (at or below for a sell, at or above for a buy)
+        #[arg(long, conflicts_with = "take_profit")]
+        stop_loss: Option<Price>,
+        /// Hold the order until a trade at or beyond this price goes in its favour (at or above for a sell, at or below for a buy)
+        #[arg(long)]
+        take_profit: Option<Price>,
+    },
+    /// Stops new orders and amendments in a market, admin only
+    PauseMarket { market: String },
+    UnpauseMarket { market: String },
+    /// Changes the fees of a market, admin only
+    SetMarketFees {
+        market: String,
+        #[arg(long, default_value = "0", allow_hyphen_values = true)]
+        maker_fee_bps: i32,
+        #[arg(long, default_value = "0")]
+        taker_fee_bps: i32,
+    },
+    /// Hands the admin role over to another identity, admin only
+    TransferAdmin { new_admin: String },
+    /// Refunds every order resting in a market and removes it, admin only
+    DelistMarket { market: String },
+    /// Moves the clock orders expire by forward, admin only
+    AdvanceClock {
+        block_height: u64,
+        /// Unix timestamp in seconds, defaults to now
+        #[arg(long)]
+        timestamp: Option<u64>,
+    },
+    /// Cancels one of the user's resting orders and refunds what it still reserves
+    CancelOrder { order_id: u64 },
+    /// Changes the price or quantity of one of the user's resting orders
+    AmendOrder { order_id: u64, price: Price, amount: u128 },
+    /// Trades the crossing orders of a batch auction market at a single price
+    ClearAuction { market: String },
+    /// Places, cancels and amends the orders listed in a JSON file in one all-or-nothing action,
+    /// e.g. `[{"CancelOrder": {"order_id": 3}}]`
+    Batch { file: String },
+    /// Prints the events or the error carried by the program outputs of a settled orderbook blob
+    DecodeOutput {
+        program_outputs: String,
+        #[arg(long)]
+        failed: bool,
+    },
+    /// Brings the state file up to the contract state from the program outputs of the orderbook
+    /// blobs that settled successfully since it was written, oldest first. Without a state file,
+    /// starts from the state the contract was registered with.
+    SyncState { program_outputs: Vec<String> },
 }
 
 #[tokio::main]
@@ -48,19 +160,21 @@ async fn main() {
 
     let cli = Cli::parse();
 
-    let client = client_sdk::rest_client::NodeApiHttpClient::new(cli.host).unwrap();
+    let client = NodeApiHttpClient::new(cli.host.clone()).unwrap();
 
     let contract_name = &cli.contract_name.clone();
 
-    let orderbook_prover = Risc0Prover::new(ZK_ORDERBOOK_ELF);
     let identity_prover = Risc0Prover::new(methods_identity::GUEST_ELF);
     let token_prover = Risc0Prover::new(methods_token::GUEST_ELF);
 
     match cli.cmd{
 
-        Commands::Register { token } => {
+        Commands::Register { fee_collector, admin } => {
+
+            let fee_collector = fee_collector.unwrap_or_else(|| cli.user.clone());
+            let admin = admin.unwrap_or_else(|| cli.user.clone());
 
-            let initial_state = OrderBookState::new(token);
+            let initial_state = OrderBookState::new(fee_collector, admin);
 
             let register_tx = RegisterContractTransaction {
                 owner: "examples".to_string(),
@@ -76,17 +190,14 @@ async fn main() {
 
             println!("✅ Register contract tx sent. Tx hash: {}", res);
 
+            save_orderbook_state(&cli.state_file, &initial_state);
+
 
         },
 
         Commands::DepositAsset { token, amount } => {
 
-            let initial_state: OrderBookState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
+            let initial_state = load_orderbook_state(&client, contract_name, &cli.state_file).await;
             println!("Initial state: {:?}", initial_state);
 
             let identity = Identity(cli.user.clone());
@@ -134,13 +245,7 @@ async fn main() {
                 blobs: blobs.clone(),
                 index: sdk::BlobIndex(2),
             };
-            let proof = orderbook_prover.prove(inputs).await.unwrap();
-            let proof_tx = ProofTransaction{
-                proof,
-                contract_name: contract_name.clone().into(),
-            };
-            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+            prove_orderbook_blob(&client, contract_name, &cli.state_file, &initial_state, inputs, None).await;
 
             // Proving token tx
             let initial_token_state: TokenContractState = client.get_contract(&token.clone().into()).await.unwrap().state.into();
@@ -187,21 +292,7 @@ async fn main() {
 
         }
 
-        Commands::InsertOrder { token, price, amount, side } => {
-
-            let initial_state: OrderBookState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
-            println!("Initial state: {:?}", initial_state);
-
-            let identity = Identity(cli.user.clone());
-
-            let identity_cf: IdentityAction = IdentityAction::VerifyIdentity { account: identity.0.clone(), nonce: cli.nonce.parse().unwrap() };
-
-            let identity_contract_name = cli.user.rsplit_once(".").unwrap().1.to_string();
+        Commands::InsertOrder { pair, price, amount, side, market, tif, expiry_height, expiry_timestamp, stp, private, peak, stop_loss, take_profit } => {
 
             let order_type = match side.as_str(){
                 "buy" => OrderType::Bid,
@@ -209,67 +300,394 @@ async fn main() {
                 &_ => panic!("Invalid side")
             };
 
-            let blobs = vec![
-                sdk::Blob{
-                    contract_name: identity_contract_name.clone().into(),
-                    data: sdk::BlobData(
-                        bincode::encode_to_vec(identity_cf, bincode::config::standard())
-                            .expect("Failed to encode identity action")
-                    ),
-                },
+            let order_kind = if market { OrderKind::Market } else { OrderKind::Limit };
 
-                sdk::Blob{
-                    contract_name: contract_name.clone().into(),
-                    data: sdk::BlobData(bincode::encode_to_vec(contract_orderbook_app::OrderBookAction::InsertOrder { order_asset: token,  order_type: order_type, order_price: price, order_quantity: amount}, bincode::config::standard()).expect("Failed to encode orderbook action"))
-                }
-            ];
+            let time_in_force = match tif.as_str(){
+                "gtc" => TimeInForce::Gtc,
+                "ioc" => TimeInForce::Ioc,
+                "fok" => TimeInForce::Fok,
+                "post-only" => TimeInForce::PostOnly,
+                &_ => panic!("Invalid time in force")
+            };
 
-            let blob_tx = BlobTransaction{
-                blobs: blobs.clone(),
-                identity: identity.clone()
+            let order_expiry = expiry_height.map(Expiry::BlockHeight).or(expiry_timestamp.map(Expiry::Timestamp));
+
+            let self_trade_prevention = match stp.as_str(){
+                "cancel-newest" => SelfTradePrevention::CancelNewest,
+                "cancel-oldest" => SelfTradePrevention::CancelOldest,
+                "cancel-both" => SelfTradePrevention::CancelBoth,
+                "decrement-and-cancel" => SelfTradePrevention::DecrementAndCancel,
+                &_ => panic!("Invalid self-trade prevention")
             };
 
-            let blob_tx = client.send_tx_blob(&blob_tx).await.unwrap();
-            println!("✅ Blob tx sent. Tx hash: {}", blob_tx);
+            let trigger = stop_loss.map(|price| (TriggerKind::StopLoss, price)).or(take_profit.map(|price| (TriggerKind::TakeProfit, price)));
 
-            // Proving orderbook tx
-            let inputs = ContractInput{
-                initial_state: initial_state.as_digest(),
-                identity: identity.clone(),
-                tx_hash: blob_tx.clone().into(),
-                private_blob: sdk::BlobData(vec![]),
-                blobs: blobs.clone(),
-                index: sdk::BlobIndex(1),
+            let (action, private_order) = match trigger{
+                Some((trigger_kind, trigger_price)) => (OrderBookAction::PlaceTriggerOrder { order_market: pair, order_type, order_kind, time_in_force, order_price: price, order_quantity: amount, order_expiry, self_trade_prevention, order_peak: peak, trigger_kind, trigger_price }, None),
+                None if private => {
+                    let private_order = PrivateOrder { order_market: pair, order_type, order_kind, time_in_force, order_price: price, order_quantity: amount, order_expiry, self_trade_prevention, order_peak: peak, salt: rand::random() };
+                    println!("Order commitment: {}", hex::encode(private_order.commitment()));
+                    (OrderBookAction::InsertPrivateOrder { commitment: private_order.commitment() }, Some(private_order))
+                }
+                None => (OrderBookAction::InsertOrder { order_market: pair,  order_type: order_type, order_kind: order_kind, time_in_force: time_in_force, order_price: price, order_quantity: amount, order_expiry: order_expiry, self_trade_prevention: self_trade_prevention, order_peak: peak}, None),
             };
-            let proof = orderbook_prover.prove(inputs).await.unwrap();
-            let proof_tx = ProofTransaction{
-                proof,
-                contract_name: contract_name.clone().into(),
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, private_order).await;
+
+        }
+
+        Commands::CreateMarket { base_token, quote_token, tick_size, lot_size, min_order_size, maker_fee_bps, taker_fee_bps, auction } => {
+
+            let config = MarketConfig {
+                base_token,
+                quote_token,
+                tick_size,
+                lot_size,
+                min_order_size,
+                fees: FeeSchedule { maker_bps: maker_fee_bps, taker_bps: taker_fee_bps },
+                status: MarketStatus::Active,
+                matching: if auction { MatchingMode::BatchAuction } else { MatchingMode::Continuous },
             };
-            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+            if !config.is_valid(){
+                panic!("Invalid market config")
+            }
 
-            // Proving identity tx
-            let initial_identity_state: IdentityContractState = client.get_contract(&identity_contract_name.clone().into()).await.unwrap().state.into();
-            let inputs = ContractInput{
-                initial_state: initial_identity_state.as_digest(),
-                identity: identity.clone(),
-                tx_hash: blob_tx.clone().into(),
-                private_blob: sdk::BlobData(cli.pass.as_bytes().to_vec()),
-                blobs: blobs.clone(),
-                index: sdk::BlobIndex(0),
+            let action = OrderBookAction::CreateMarket { config };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::PauseMarket { market } => {
+
+            let action = OrderBookAction::PauseMarket { market };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::UnpauseMarket { market } => {
+
+            let action = OrderBookAction::UnpauseMarket { market };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::SetMarketFees { market, maker_fee_bps, taker_fee_bps } => {
+
+            let fees = FeeSchedule { maker_bps: maker_fee_bps, taker_bps: taker_fee_bps };
+            if !fees.is_valid(){
+                panic!("Invalid fee schedule")
+            }
+
+            let action = OrderBookAction::SetMarketFees { market, fees };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::TransferAdmin { new_admin } => {
+
+            let action = OrderBookAction::TransferAdmin { new_admin };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::DelistMarket { market } => {
+
+            let action = OrderBookAction::DelistMarket { market };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::AdvanceClock { block_height, timestamp } => {
+
+            let timestamp = timestamp.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+            let action = OrderBookAction::AdvanceClock { clock: Clock { block_height, timestamp } };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::CancelOrder { order_id } => {
+
+            let action = OrderBookAction::CancelOrder { order_id };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::AmendOrder { order_id, price, amount } => {
+
+            let action = OrderBookAction::AmendOrder { order_id, new_price: price, new_quantity: amount };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::ClearAuction { market } => {
+
+            let action = OrderBookAction::ClearAuction { market };
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::Batch { file } => {
+
+            let batch = std::fs::read_to_string(file).expect("Could not read the batch file");
+            let sub_actions: Vec<SubAction> = serde_json::from_str(&batch).expect("Invalid batch file");
+
+            let action = OrderBookAction::Batch(sub_actions);
+
+            send_orderbook_action(&client, contract_name, &cli.user, &cli.pass, &cli.nonce, &cli.state_file, action, None).await;
+
+        }
+
+        Commands::DecodeOutput { program_outputs, failed } => {
+
+            print_program_outputs(!failed, program_outputs.as_bytes());
+
+        }
+
+        Commands::SyncState { program_outputs } => {
+
+            let onchain: StateCommitment = client.get_contract(&contract_name.clone().into()).await.unwrap().state.into();
+
+            let state = match std::fs::read(&cli.state_file){
+                Ok(bytes) => bincode::decode_from_slice(&bytes, bincode::config::standard()).expect("Could not decode the orderbook state file").0,
+                Err(_) => OrderBookState::new(onchain.fee_collector.clone(), onchain.admin.clone()),
             };
-            let proof = identity_prover.prove(inputs).await.unwrap();
-            let proof_tx = ProofTransaction{
-                proof,
-                contract_name: identity_contract_name.clone().into(),
+
+            let outputs: Vec<ActionOutput> = program_outputs.iter()
+                .map(|program_outputs| ActionOutput::decode(program_outputs.as_bytes()).expect("Not the program outputs of a successful orderbook blob"))
+                .collect();
+
+            let Some(state) = apply_outputs(state, &outputs, &onchain) else {
+                panic!("Synced state does not match the contract state, program outputs are missing or out of order");
             };
-            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+            save_orderbook_state(&cli.state_file, &state);
+            let _ = std::fs::remove_file(pending_output_file(&cli.state_file));
+            println!("✅ Orderbook state synced");
+
+        }
+
+    }
+
 
+}
+
+/// Sends a blob tx made of the user's identity blob followed by an orderbook `action`, then proves both blobs.
+/// `private_order` only goes to the orderbook prover, in the private input of the proof
+async fn send_orderbook_action(
+    client: &NodeApiHttpClient,
+    contract_name: &String,
+    user: &String,
+    pass: &String,
+    nonce: &String,
+    state_file: &String,
+    action: OrderBookAction,
+    private_order: Option<PrivateOrder>,
+){
+
+    let identity_prover = Risc0Prover::new(methods_identity::GUEST_ELF);
+
+    let initial_state = load_orderbook_state(client, contract_name, state_file).await;
+    println!("Initial state: {:?}", initial_state);
+
+    let identity = Identity(user.clone());
+
+    let identity_cf: IdentityAction = IdentityAction::VerifyIdentity { account: identity.0.clone(), nonce: nonce.parse().unwrap() };
+
+    let identity_contract_name = user.rsplit_once(".").unwrap().1.to_string();
+
+    let blobs = vec![
+        sdk::Blob{
+            contract_name: identity_contract_name.clone().into(),
+            data: sdk::BlobData(
+                bincode::encode_to_vec(identity_cf, bincode::config::standard())
+                    .expect("Failed to encode identity action")
+            ),
+        },
+
+        sdk::Blob{
+            contract_name: contract_name.clone().into(),
+            data: sdk::BlobData(bincode::encode_to_vec(action, bincode::config::standard()).expect("Failed to encode orderbook action"))
+        }
+    ];
+
+    let blob_tx = BlobTransaction{
+        blobs: blobs.clone(),
+        identity: identity.clone()
+    };
+
+    let blob_tx = client.send_tx_blob(&blob_tx).await.unwrap();
+    println!("✅ Blob tx sent. Tx hash: {}", blob_tx);
+
+    // Proving orderbook tx
+    let inputs = ContractInput{
+        initial_state: initial_state.as_digest(),
+        identity: identity.clone(),
+        tx_hash: blob_tx.clone().into(),
+        private_blob: sdk::BlobData(vec![]),
+        blobs: blobs.clone(),
+        index: sdk::BlobIndex(1),
+    };
+    prove_orderbook_blob(client, contract_name, state_file, &initial_state, inputs, private_order).await;
+
+    // Proving identity tx
+    let initial_identity_state: IdentityContractState = client.get_contract(&identity_contract_name.clone().into()).await.unwrap().state.into();
+    let inputs = ContractInput{
+        initial_state: initial_identity_state.as_digest(),
+        identity: identity.clone(),
+        tx_hash: blob_tx.clone().into(),
+        private_blob: sdk::BlobData(pass.as_bytes().to_vec()),
+        blobs: blobs.clone(),
+        index: sdk::BlobIndex(0),
+    };
+    let proof = identity_prover.prove(inputs).await.unwrap();
+    let proof_tx = ProofTransaction{
+        proof,
+        contract_name: identity_contract_name.clone().into(),
+    };
+    let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
+    println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+
+}
+
+/// Reads the full orderbook state from `state_file`, checking it against the commitment on chain.
+/// The state file is one blob behind once the last blob proved from it settled, so its program output
+/// is applied then.
+async fn load_orderbook_state(client: &NodeApiHttpClient, contract_name: &String, state_file: &String) -> OrderBookState{
+
+    let onchain: StateCommitment = client
+        .get_contract(&contract_name.clone().into())
+        .await
+        .unwrap()
+        .state
+        .into();
+
+    let bytes = std::fs::read(state_file).expect("Could not read the orderbook state file, it is written when registering the contract or by sync-state");
+    let (state, _): (OrderBookState, usize) = bincode::decode_from_slice(&bytes, bincode::config::standard())
+        .expect("Could not decode the orderbook state file");
+
+    if commitment::commit(&state) == onchain{
+        return state;
+    }
+
+    let pending = std::fs::read(pending_output_file(state_file)).ok().and_then(|bytes| ActionOutput::decode(&bytes));
+    let Some(state) = pending.and_then(|output| apply_outputs(state, &[output], &onchain)) else {
+        panic!("Orderbook state file does not match the contract state, bring it up to date with sync-state");
+    };
+    save_orderbook_state(state_file, &state);
+    let _ = std::fs::remove_file(pending_output_file(state_file));
+
+    state
+
+}
+
+/// Applies the state changes of settled orderbook blobs to `state`, oldest first, giving the state
+/// they lead to if it is the one on chain
+fn apply_outputs(mut state: OrderBookState, outputs: &[ActionOutput], onchain: &StateCommitment) -> Option<OrderBookState>{
+
+    for output in outputs{
+        state = commitment::apply(&state, &output.changes).ok()?;
+    }
+
+    // What the contract keeps in clear is not in the changes, only in its state
+    state.admin = onchain.admin.clone();
+    state.fee_collector = onchain.fee_collector.clone();
+    state.order_nonce = onchain.order_nonce;
+    state.clock = onchain.clock;
+
+    (commitment::commit(&state) == *onchain).then_some(state)
+
+}
+
+/// Where the program output of the last orderbook blob proved from `state_file` waits for it to settle
+fn pending_output_file(state_file: &String) -> String{
+    format!("{}.pending", state_file)
+}
+
+fn save_orderbook_state(state_file: &String, state: &OrderBookState){
+
+    let bytes = bincode::encode_to_vec(state, bincode::config::standard()).expect("Failed to encode orderbook state");
+    std::fs::write(state_file, bytes).expect("Could not write the orderbook state file");
+
+}
+
+/// Proves the orderbook blob of `inputs` against the full `state`. The state file is left as is, as the
+/// transaction may still fail to settle, and the program output kept for [`load_orderbook_state`] instead.
+///
+/// The guest only gets the leaves the action touches. Those it writes are known from running the
+/// action here, those it only reads are asked for by the contract one at a time.
+async fn prove_orderbook_blob(
+    client: &NodeApiHttpClient,
+    contract_name: &String,
+    state_file: &String,
+    state: &OrderBookState,
+    inputs: ContractInput,
+    private_order: Option<PrivateOrder>,
+){
+
+    let next_state = contract_orderbook_app::run(state.clone(), &inputs, private_order.clone()).ok().map(|contract| contract.state);
+    let mut keys = next_state.as_ref().map(|next_state| commitment::changed_keys(state, next_state)).unwrap_or_default();
+
+    let (inputs, output) = loop{
+        let private_input = PrivateInput{ witness: commitment::witness(state, &keys), order: private_order.clone() };
+        let inputs = ContractInput{
+            private_blob: sdk::BlobData(bincode::encode_to_vec(private_input, bincode::config::standard()).expect("Failed to encode private input")),
+            ..inputs.clone()
+        };
+
+        match contract_orderbook_app::try_execute(inputs.clone()){
+            Ok(output) => break (inputs, output),
+            Err(OrderBookError::MissingWitness(key)) => {
+                if !keys.insert(key.clone()){
+                    panic!("Contract asks again for {:?}", key);
+                }
+            }
+            Err(e) => panic!("Contract rejects the private input: {}", e),
         }
+    };
+
+    let orderbook_prover = Risc0Prover::new(ZK_ORDERBOOK_ELF);
 
+    print_program_outputs(output.success, &output.program_outputs);
+    let proof = orderbook_prover.prove(inputs).await.unwrap();
+    let proof_tx = ProofTransaction{
+        proof,
+        contract_name: contract_name.clone().into(),
+    };
+    let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
+    println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+
+    if output.success{
+        std::fs::write(pending_output_file(state_file), &output.program_outputs).expect("Could not write the pending program output");
     }
 
+}
+
+fn print_program_outputs(success: bool, program_outputs: &[u8]){
 
-}
\ No newline at end of file
+    if success{
+        match ActionOutput::decode(program_outputs){
+            Some(output) => {
+                for event in output.events{
+                    println!("Orderbook event: {:?}", event);
+                }
+            }
+            None => println!("Orderbook output: {}", String::from_utf8_lossy(program_outputs)),
+        }
+    }else{
+        match OrderBookError::decode_output(program_outputs){
+            Some(error) => println!("❌ Orderbook rejected the action: {}", error),
+            None => println!("❌ Orderbook rejected the action: {}", String::from_utf8_lossy(program_outputs)),
+        }
+    }
+
+}